@@ -0,0 +1,187 @@
+use core::{fmt::Debug, future::Future};
+use std::future;
+
+use rand::{CryptoRng, RngCore};
+
+use crate::KeyManagementScheme;
+
+/// An asynchronous counterpart to `KeyManagementScheme`, for schemes that await I/O.
+///
+/// Every future is `Send`, so schemes can be driven from tasks on a multi-threaded runtime. A
+/// `KeyManagementScheme` can be used through this trait by wrapping it in [`Blocking`].
+pub trait AsyncKeyManagementScheme: Send {
+    /// The type of a key.
+    type Key: Send;
+    /// The type used to act as key identifiers.
+    type KeyId: Send;
+    /// The associated error for fallible operations (e.g. `persist()`).
+    type Error: Debug + Send;
+
+    /// Derive the key corresponding to the given `KeyId`.
+    ///
+    /// This key should not be kept beyond any updates to that `KeyId`.
+    fn derive(
+        &mut self,
+        key: Self::KeyId,
+    ) -> impl Future<Output = Result<Self::Key, Self::Error>> + Send;
+
+    /// Update the key corresponding to the given `KeyId`.
+    ///
+    /// Revocation of the old key is only guaranteed after calling `commit()`.
+    fn update(
+        &mut self,
+        key: Self::KeyId,
+    ) -> impl Future<Output = Result<Self::Key, Self::Error>> + Send;
+
+    /// Derive the keys corresponding to each of the given `KeyId`s, in order.
+    ///
+    /// Schemes may override this to share work between neighbouring `KeyId`s.
+    fn derive_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId, IntoIter: Send> + Send,
+    ) -> impl Future<Output = Result<Vec<Self::Key>, Self::Error>> + Send {
+        async move {
+            let mut derived = Vec::new();
+            for key in keys {
+                derived.push(self.derive(key).await?);
+            }
+            Ok(derived)
+        }
+    }
+
+    /// Update the keys corresponding to each of the given `KeyId`s, in order.
+    ///
+    /// Schemes may override this to share work between neighbouring `KeyId`s.
+    fn update_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId, IntoIter: Send> + Send,
+    ) -> impl Future<Output = Result<Vec<Self::Key>, Self::Error>> + Send {
+        async move {
+            let mut updated = Vec::new();
+            for key in keys {
+                updated.push(self.update(key).await?);
+            }
+            Ok(updated)
+        }
+    }
+
+    /// Revoke the key corresponding to the given `KeyId`, without replacing it.
+    ///
+    /// Revocation is only guaranteed after calling `commit()`, after which deriving the `KeyId`
+    /// yields a fresh key unrelated to the revoked one. Until then, schemes may refuse to derive
+    /// the `KeyId`. Updating the `KeyId` before then cancels the revocation.
+    fn revoke(&mut self, key: Self::KeyId) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Commits any deferred key updates and revocations, guaranteeing their revocation from
+    /// `self`, assuming that all keys which persisted `self` in the past are securely deleted.
+    ///
+    /// Returns every `KeyId` whose key changed, paired with its new key, or `None` if it was
+    /// revoked.
+    #[allow(clippy::type_complexity)]
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng + Send,
+    ) -> impl Future<Output = Result<Vec<(Self::KeyId, Option<Self::Key>)>, Self::Error>> + Send;
+
+    /// Commits as by `commit()`, but passes every `KeyId` whose key changed to `visit` as it is
+    /// produced, paired with its new key, or `None` if it was revoked.
+    ///
+    /// Schemes may override this to avoid collecting every changed key at once.
+    fn commit_with(
+        &mut self,
+        rng: impl RngCore + CryptoRng + Send,
+        mut visit: impl FnMut(Self::KeyId, Option<Self::Key>) + Send,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move {
+            for (key, new) in self.commit(rng).await? {
+                visit(key, new);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Adapts a `KeyManagementScheme` to `AsyncKeyManagementScheme`.
+///
+/// Each operation runs to completion on the calling task, and returns a future that is already
+/// resolved, so the wrapped scheme should not block on slow I/O.
+pub struct Blocking<K> {
+    inner: K,
+}
+
+impl<K> Blocking<K> {
+    /// Wraps `inner`.
+    pub fn new(inner: K) -> Self {
+        Self { inner }
+    }
+
+    /// Borrows the wrapped scheme.
+    pub fn inner(&mut self) -> &mut K {
+        &mut self.inner
+    }
+
+    /// Unwraps the scheme.
+    pub fn into_inner(self) -> K {
+        self.inner
+    }
+}
+
+impl<K> AsyncKeyManagementScheme for Blocking<K>
+where
+    K: KeyManagementScheme + Send,
+    K::Key: Send,
+    K::KeyId: Send,
+    K::Error: Send,
+{
+    type Key = K::Key;
+    type KeyId = K::KeyId;
+    type Error = K::Error;
+
+    fn derive(
+        &mut self,
+        key: Self::KeyId,
+    ) -> impl Future<Output = Result<Self::Key, Self::Error>> + Send {
+        future::ready(self.inner.derive(key))
+    }
+
+    fn update(
+        &mut self,
+        key: Self::KeyId,
+    ) -> impl Future<Output = Result<Self::Key, Self::Error>> + Send {
+        future::ready(self.inner.update(key))
+    }
+
+    fn derive_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId, IntoIter: Send> + Send,
+    ) -> impl Future<Output = Result<Vec<Self::Key>, Self::Error>> + Send {
+        future::ready(self.inner.derive_many(keys))
+    }
+
+    fn update_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId, IntoIter: Send> + Send,
+    ) -> impl Future<Output = Result<Vec<Self::Key>, Self::Error>> + Send {
+        future::ready(self.inner.update_many(keys))
+    }
+
+    fn revoke(&mut self, key: Self::KeyId) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        future::ready(self.inner.revoke(key))
+    }
+
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng + Send,
+    ) -> impl Future<Output = Result<Vec<(Self::KeyId, Option<Self::Key>)>, Self::Error>> + Send
+    {
+        future::ready(self.inner.commit(rng))
+    }
+
+    fn commit_with(
+        &mut self,
+        rng: impl RngCore + CryptoRng + Send,
+        visit: impl FnMut(Self::KeyId, Option<Self::Key>) + Send,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        future::ready(self.inner.commit_with(rng, visit))
+    }
+}
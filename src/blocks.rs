@@ -0,0 +1,311 @@
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    KeyInit, XChaCha20Poly1305, XNonce,
+};
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+
+use crate::{codec::invalid, BlockStore, Key, KmsError, Reencryptor, Storage, Versioned};
+
+/// The length of the nonce stored with each block.
+const NONCE_LEN: usize = 24;
+
+/// The length of the tag authenticating each block.
+const TAG_LEN: usize = 16;
+
+/// A byte-addressed device holding the slots of an encrypted block store.
+pub trait Device {
+    /// Returns the size of the device in bytes.
+    fn size(&mut self) -> Result<u64, KmsError>;
+
+    /// Fills `buf` with the bytes at `offset`, failing with [`io::ErrorKind::UnexpectedEof`] if
+    /// they do not all lie within the device.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), KmsError>;
+
+    /// Writes `bytes` at `offset`, growing the device if needed.
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<(), KmsError>;
+
+    /// Durably records every write so far.
+    fn flush(&mut self) -> Result<(), KmsError>;
+}
+
+impl Device for File {
+    fn size(&mut self) -> Result<u64, KmsError> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), KmsError> {
+        self.seek(SeekFrom::Start(offset))?;
+        Ok(self.read_exact(buf)?)
+    }
+
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<(), KmsError> {
+        self.seek(SeekFrom::Start(offset))?;
+        Ok(self.write_all(bytes)?)
+    }
+
+    fn flush(&mut self) -> Result<(), KmsError> {
+        Ok(self.sync_data()?)
+    }
+}
+
+impl Device for Vec<u8> {
+    fn size(&mut self) -> Result<u64, KmsError> {
+        Ok(self.len() as u64)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), KmsError> {
+        let bytes = usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.get(start..start.checked_add(buf.len())?))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<(), KmsError> {
+        let (start, end) = usize::try_from(offset)
+            .ok()
+            .and_then(|start| Some((start, start.checked_add(bytes.len())?)))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        if self.len() < end {
+            self.resize(end, 0);
+        }
+        self[start..end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KmsError> {
+        Ok(())
+    }
+}
+
+/// Encrypts the contents of a block under a fresh nonce, binding them to the block's number.
+pub(crate) fn seal(key: &Key, block: u64, plaintext: &[u8]) -> Result<Vec<u8>, KmsError> {
+    let mut nonce = XNonce::default();
+    OsRng.try_fill_bytes(&mut nonce)?;
+
+    let mut bytes = nonce.to_vec();
+    let sealed = XChaCha20Poly1305::new(key.as_bytes().into()).encrypt(
+        &nonce,
+        Payload {
+            msg: plaintext,
+            aad: &block.to_le_bytes(),
+        },
+    );
+    bytes.extend(sealed.expect("block fits in a single message"));
+    Ok(bytes)
+}
+
+/// Decrypts the contents of a block sealed by `seal()`.
+pub(crate) fn open(key: &Key, block: u64, ciphertext: &[u8]) -> Result<Vec<u8>, KmsError> {
+    if ciphertext.len() < NONCE_LEN {
+        return Err(invalid("truncated block"));
+    }
+    let (nonce, sealed) = ciphertext.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(key.as_bytes().into())
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: sealed,
+                aad: &block.to_le_bytes(),
+            },
+        )
+        .map_err(|_| invalid("block failed authentication"))
+}
+
+/// The slots of a device, each holding a flag, a nonce, and an encrypted block.
+struct Slots<D> {
+    device: D,
+    block_size: usize,
+    live: BTreeSet<u64>,
+}
+
+impl<D: Device> Slots<D> {
+    /// Scans the device for slots holding blocks.
+    fn open(mut device: D, block_size: usize) -> Result<Self, KmsError> {
+        let slot_len = (1 + NONCE_LEN + block_size + TAG_LEN) as u64;
+        let mut live = BTreeSet::new();
+        for block in 0..device.size()? / slot_len {
+            let mut flag = [0];
+            device.read_at(block * slot_len, &mut flag)?;
+            if flag[0] != 0 {
+                live.insert(block);
+            }
+        }
+
+        Ok(Self {
+            device,
+            block_size,
+            live,
+        })
+    }
+
+    /// Returns where the slot of a block starts, failing if the slot lies past the end of the
+    /// `u64` range.
+    fn offset(&self, block: u64) -> Result<u64, KmsError> {
+        let slot_len = (1 + NONCE_LEN + self.block_size + TAG_LEN) as u64;
+        block
+            .checked_mul(slot_len)
+            .filter(|start| start.checked_add(slot_len).is_some())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "block cannot be addressed").into()
+            })
+    }
+
+    /// Overwrites the slot of a block with zeros.
+    fn clear(&mut self, block: u64) -> Result<(), KmsError> {
+        let zeros = vec![0; 1 + NONCE_LEN + self.block_size + TAG_LEN];
+        self.device.write_at(self.offset(block)?, &zeros)?;
+        self.device.flush()?;
+        self.live.remove(&block);
+        Ok(())
+    }
+}
+
+impl<D: Device> BlockStore for Slots<D> {
+    fn blocks(&mut self) -> Result<Vec<u64>, KmsError> {
+        Ok(self.live.iter().copied().collect())
+    }
+
+    fn read(&mut self, block: u64) -> Result<Vec<u8>, KmsError> {
+        if !self.live.contains(&block) {
+            return Err(KmsError::UnknownKey);
+        }
+        let mut slot = vec![0; 1 + NONCE_LEN + self.block_size + TAG_LEN];
+        self.device.read_at(self.offset(block)?, &mut slot)?;
+        slot.remove(0);
+        Ok(slot)
+    }
+
+    fn write(&mut self, block: u64, bytes: &[u8]) -> Result<(), KmsError> {
+        let mut slot = Vec::with_capacity(1 + bytes.len());
+        slot.push(1);
+        slot.extend_from_slice(bytes);
+        self.device.write_at(self.offset(block)?, &slot)?;
+        self.device.flush()?;
+        self.live.insert(block);
+        Ok(())
+    }
+
+    fn encrypt(&self, key: &Key, block: u64, plaintext: &[u8]) -> Result<Vec<u8>, KmsError> {
+        seal(key, block, plaintext)
+    }
+
+    fn decrypt(&self, key: &Key, block: u64, ciphertext: &[u8]) -> Result<Vec<u8>, KmsError> {
+        open(key, block, ciphertext)
+    }
+}
+
+/// A store of fixed-size blocks on a device, each encrypted under the key its number derives.
+///
+/// Every write re-keys the block with `update()`, and deleting a block re-keys it and wipes its
+/// slot. Once `sync()` commits the scheme, the replaced keys are gone, so copies of old
+/// ciphertext can no longer be decrypted. Blocks whose keys change in the commit are re-encrypted
+/// by a [`Reencryptor`] sharing the scheme's storage, so an interrupted sync is finished when the
+/// store is next opened.
+///
+/// Blocks written since the last sync are lost on a crash, along with the keys they were written
+/// under.
+pub struct EncryptedBlockStore<K, D, S> {
+    kms: K,
+    slots: Slots<D>,
+    reencryptor: Reencryptor<S>,
+}
+
+impl<K, D, S> EncryptedBlockStore<K, D, S>
+where
+    K: Versioned<KeyId = u64, Key = Key>,
+    K::Error: From<KmsError>,
+    D: Device,
+    S: Storage,
+{
+    /// Creates a store over `device` with a fresh scheme, persisting the scheme to the blob of
+    /// `storage` named `name`.
+    pub fn create(
+        mut kms: K,
+        mut storage: S,
+        name: &str,
+        device: D,
+        block_size: usize,
+    ) -> Result<Self, K::Error> {
+        kms.persist_to(&mut storage, name)?;
+        Ok(Self {
+            kms,
+            slots: Slots::open(device, block_size)?,
+            reencryptor: Reencryptor::new(storage, name),
+        })
+    }
+
+    /// Opens a store previously created over `device`, finishing any interrupted sync.
+    pub fn open(
+        mut storage: S,
+        name: &str,
+        device: D,
+        block_size: usize,
+    ) -> Result<Self, K::Error> {
+        let kms = K::load_from(&mut storage, name)?;
+        let mut store = Self {
+            kms,
+            slots: Slots::open(device, block_size)?,
+            reencryptor: Reencryptor::new(storage, name),
+        };
+        store.reencryptor.resume(&mut store.kms, &mut store.slots)?;
+        Ok(store)
+    }
+
+    /// Returns the size of each block.
+    pub fn block_size(&self) -> usize {
+        self.slots.block_size
+    }
+
+    /// Reads and decrypts a block, or returns `None` if it holds no data.
+    pub fn read(&mut self, block: u64) -> Result<Option<Vec<u8>>, K::Error> {
+        if !self.slots.live.contains(&block) {
+            return Ok(None);
+        }
+        let key = self.kms.derive(block)?;
+        let ciphertext = self.slots.read(block)?;
+        Ok(Some(self.slots.decrypt(&key, block, &ciphertext)?))
+    }
+
+    /// Encrypts `data` under a fresh key for the block and writes it.
+    ///
+    /// Fails with [`KmsError::Io`] of kind [`io::ErrorKind::InvalidInput`] if `data` is not
+    /// exactly [`block_size()`](Self::block_size) bytes long, or if the block lies past the end of
+    /// the device's `u64` offsets.
+    pub fn write(&mut self, block: u64, data: &[u8]) -> Result<(), K::Error> {
+        if data.len() != self.slots.block_size {
+            return Err(KmsError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "data does not match the block size",
+            ))
+            .into());
+        }
+        self.slots.offset(block)?;
+        let key = self.kms.update(block)?;
+        let ciphertext = self.slots.encrypt(&key, block, data)?;
+        Ok(self.slots.write(block, &ciphertext)?)
+    }
+
+    /// Wipes a block and replaces its key, so that it cannot be recovered after the next sync.
+    pub fn delete(&mut self, block: u64) -> Result<(), K::Error> {
+        if self.slots.live.contains(&block) {
+            self.kms.update(block)?;
+            self.slots.clear(block)?;
+        }
+        Ok(())
+    }
+
+    /// Commits the scheme and re-encrypts the blocks whose keys changed.
+    pub fn sync(&mut self, rng: impl RngCore + CryptoRng) -> Result<(), K::Error> {
+        self.reencryptor
+            .commit(&mut self.kms, &mut self.slots, rng)
+            .map(|_| ())
+    }
+}
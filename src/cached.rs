@@ -0,0 +1,257 @@
+use std::{collections::BTreeMap, mem, ops::Range};
+
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+use crate::{Abort, KeyManagementScheme, UpdateRange};
+
+/// Hit, miss, and eviction counts of a [`Cached`] scheme.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of derivations served from the cache.
+    pub hits: u64,
+    /// The number of derivations passed through to the wrapped scheme.
+    pub misses: u64,
+    /// The number of keys evicted to make room for others.
+    pub evictions: u64,
+}
+
+/// A scheme that memoizes derived keys in a least-recently-used cache.
+///
+/// Cached keys are dropped when their `KeyId` is updated or revoked, or when a commit changes
+/// them, and are zeroized as they leave the cache.
+pub struct Cached<K: KeyManagementScheme> {
+    inner: K,
+    capacity: usize,
+    entries: BTreeMap<K::KeyId, (K::Key, u64)>,
+    recency: BTreeMap<u64, K::KeyId>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl<K> Cached<K>
+where
+    K: KeyManagementScheme,
+    K::KeyId: Ord + Clone,
+    K::Key: Clone + Zeroize,
+{
+    /// Caches up to `capacity` keys derived from `inner`.
+    pub fn new(inner: K, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            entries: BTreeMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Returns the hit, miss, and eviction counts so far.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Returns the number of cached keys.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether no keys are cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drops every cached key.
+    pub fn clear(&mut self) {
+        self.recency.clear();
+        for (_, (mut key, _)) in mem::take(&mut self.entries) {
+            key.zeroize();
+        }
+    }
+
+    /// Returns the wrapped scheme, dropping every cached key.
+    pub fn into_inner(mut self) -> K {
+        self.clear();
+        self.inner
+    }
+
+    /// Returns a cached key, marking it as the most recently used.
+    fn lookup(&mut self, id: &K::KeyId) -> Option<K::Key> {
+        let (key, stamp) = self.entries.get_mut(id)?;
+        self.recency.remove(stamp);
+        self.clock += 1;
+        *stamp = self.clock;
+        self.recency.insert(self.clock, id.clone());
+        Some(key.clone())
+    }
+
+    /// Caches a key, evicting the least recently used ones if the cache is full.
+    fn insert(&mut self, id: K::KeyId, key: K::Key) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.invalidate(&id);
+        self.clock += 1;
+        self.recency.insert(self.clock, id.clone());
+        self.entries.insert(id, (key, self.clock));
+
+        while self.entries.len() > self.capacity {
+            let Some((_, lru)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((mut key, _)) = self.entries.remove(&lru) {
+                key.zeroize();
+                self.stats.evictions += 1;
+            }
+        }
+    }
+
+    /// Drops the cached key for `id`, if any.
+    fn invalidate(&mut self, id: &K::KeyId) {
+        evict(&mut self.entries, &mut self.recency, id);
+    }
+}
+
+/// Drops the cached key for `id` from a cache's entries and recency order, if any.
+fn evict<I: Ord, V: Zeroize>(
+    entries: &mut BTreeMap<I, (V, u64)>,
+    recency: &mut BTreeMap<u64, I>,
+    id: &I,
+) {
+    if let Some((mut key, stamp)) = entries.remove(id) {
+        recency.remove(&stamp);
+        key.zeroize();
+    }
+}
+
+impl<K> KeyManagementScheme for Cached<K>
+where
+    K: KeyManagementScheme,
+    K::KeyId: Ord + Clone,
+    K::Key: Clone + Zeroize,
+{
+    type Key = K::Key;
+    type KeyId = K::KeyId;
+    type Error = K::Error;
+
+    fn derive(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        if let Some(cached) = self.lookup(&key) {
+            self.stats.hits += 1;
+            return Ok(cached);
+        }
+
+        self.stats.misses += 1;
+        let derived = self.inner.derive(key.clone())?;
+        self.insert(key, derived.clone());
+        Ok(derived)
+    }
+
+    /// Derives the keys missing from the cache in a single batch from the wrapped scheme.
+    fn derive_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        let keys: Vec<_> = keys.into_iter().collect();
+        let mut derived: Vec<_> = keys.iter().map(|key| self.lookup(key)).collect();
+        let missing: Vec<_> = keys
+            .iter()
+            .zip(&derived)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.stats.hits += (keys.len() - missing.len()) as u64;
+        self.stats.misses += missing.len() as u64;
+
+        let mut fresh = self.inner.derive_many(missing)?.into_iter();
+        for (key, slot) in keys.into_iter().zip(&mut derived) {
+            if slot.is_none() {
+                let fresh = fresh.next().expect("scheme derived every missing key");
+                self.insert(key, fresh.clone());
+                *slot = Some(fresh);
+            }
+        }
+        Ok(derived.into_iter().flatten().collect())
+    }
+
+    fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.invalidate(&key);
+        self.inner.update(key)
+    }
+
+    fn update_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        let keys: Vec<_> = keys.into_iter().collect();
+        for key in &keys {
+            self.invalidate(key);
+        }
+        self.inner.update_many(keys)
+    }
+
+    fn revoke(&mut self, key: Self::KeyId) -> Result<(), Self::Error> {
+        self.invalidate(&key);
+        self.inner.revoke(key)
+    }
+
+    /// Commits the wrapped scheme, dropping every cached key that changed.
+    ///
+    /// If the commit fails, the whole cache is dropped.
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Option<Self::Key>)>, Self::Error> {
+        let mut changes = Vec::new();
+        self.commit_with(rng, |id, key| changes.push((id, key)))?;
+        Ok(changes)
+    }
+
+    fn commit_with(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+        mut visit: impl FnMut(Self::KeyId, Option<Self::Key>),
+    ) -> Result<(), Self::Error> {
+        let (entries, recency) = (&mut self.entries, &mut self.recency);
+        let committed = self.inner.commit_with(rng, |id, key| {
+            evict(entries, recency, &id);
+            visit(id, key)
+        });
+
+        if committed.is_err() {
+            self.clear();
+        }
+        committed
+    }
+}
+
+impl<K> Abort for Cached<K>
+where
+    K: Abort,
+    K::KeyId: Ord + Clone,
+    K::Key: Clone + Zeroize,
+{
+    fn abort(&mut self) {
+        self.clear();
+        self.inner.abort();
+    }
+}
+
+impl<K> UpdateRange for Cached<K>
+where
+    K: UpdateRange,
+    K::Key: Clone + Zeroize,
+{
+    fn update_range(&mut self, keys: Range<u64>) -> Result<(), Self::Error> {
+        let stale: Vec<_> = self
+            .entries
+            .range(keys.clone())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in stale {
+            self.invalidate(&id);
+        }
+        self.inner.update_range(keys)
+    }
+}
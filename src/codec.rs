@@ -0,0 +1,38 @@
+//! Helpers for the fixed-width, little-endian encoding of persisted state.
+
+use std::io::{self, Read, Write};
+
+use crate::{Key, KmsError};
+
+pub(crate) fn write_u64(writer: &mut impl Write, n: u64) -> Result<(), KmsError> {
+    Ok(writer.write_all(&n.to_le_bytes())?)
+}
+
+pub(crate) fn read_u64(reader: &mut impl Read) -> Result<u64, KmsError> {
+    read_array(reader).map(u64::from_le_bytes)
+}
+
+pub(crate) fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], KmsError> {
+    let mut bytes = [0; N];
+    read_exact(reader, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Reads a key straight into its zeroizing storage, so no stray copy of it is left behind.
+pub(crate) fn read_key(reader: &mut impl Read) -> Result<Key, KmsError> {
+    let mut key = Key::default();
+    read_exact(reader, key.as_mut_bytes())?;
+    Ok(key)
+}
+
+fn read_exact(reader: &mut impl Read, bytes: &mut [u8]) -> Result<(), KmsError> {
+    reader.read_exact(bytes).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("truncated state"),
+        _ => err.into(),
+    })
+}
+
+/// Creates an error for persisted state that fails to decode.
+pub(crate) fn invalid(reason: &str) -> KmsError {
+    KmsError::Corrupted(reason.into())
+}
@@ -0,0 +1,348 @@
+//! Generic checks for the contract every `KeyManagementScheme` must uphold.
+//!
+//! Each check takes a fresh scheme and a handful of distinct key ids to exercise, and panics if
+//! the scheme misbehaves. The [`conformance_tests!`](crate::conformance_tests) macro expands to a
+//! test per check:
+//!
+//! ```ignore
+//! kms::conformance_tests!(khf, || Khf::new(OsRng, &[4, 4]), [0, 1, 5, 16, 17]);
+//! ```
+
+use std::{collections::BTreeMap, fmt::Debug};
+
+use rand::rngs::OsRng;
+
+use crate::{Abort, KeyManagementScheme, UpdateRange};
+
+/// Runs every check against schemes built by `new`.
+pub fn check<K>(mut new: impl FnMut() -> K, ids: &[K::KeyId])
+where
+    K: KeyManagementScheme,
+    K::Key: PartialEq + Debug,
+    K::KeyId: Clone + Ord + Debug,
+{
+    derive_is_stable(&mut new(), ids);
+    update_changes_key(&mut new(), ids);
+    commit_returns_changed_keys(&mut new(), ids);
+    revoke_discards_key(&mut new(), ids);
+    commit_with_visits_changed_keys(&mut new(), ids);
+    derive_many_matches_derive(&mut new(), ids);
+}
+
+/// Checks that deriving a key repeatedly yields the same key until it is updated.
+pub fn derive_is_stable<K>(kms: &mut K, ids: &[K::KeyId])
+where
+    K: KeyManagementScheme,
+    K::Key: PartialEq + Debug,
+    K::KeyId: Clone + Ord + Debug,
+{
+    let keys = derive_all(kms, ids);
+    assert_eq!(keys, derive_all(kms, ids), "derive is not stable");
+
+    let changes = kms.commit(OsRng).expect("commit failed");
+    assert!(changes.is_empty(), "commit without updates changed keys");
+    assert_eq!(
+        keys,
+        derive_all(kms, ids),
+        "derive is not stable across commit"
+    );
+}
+
+/// Checks that updating a key yields a different key, which is then derived.
+pub fn update_changes_key<K>(kms: &mut K, ids: &[K::KeyId])
+where
+    K: KeyManagementScheme,
+    K::Key: PartialEq + Debug,
+    K::KeyId: Clone + Ord + Debug,
+{
+    for id in ids {
+        let old = kms.derive(id.clone()).expect("derive failed");
+        let new = kms.update(id.clone()).expect("update failed");
+        assert_ne!(old, new, "update of {id:?} returned the old key");
+
+        let derived = kms.derive(id.clone()).expect("derive failed");
+        assert_eq!(derived, new, "derive of {id:?} ignored its update");
+    }
+}
+
+/// Checks that `commit()` returns exactly the keys whose value it changed, including every key
+/// updated since the last commit, and nothing more on a second commit.
+pub fn commit_returns_changed_keys<K>(kms: &mut K, ids: &[K::KeyId])
+where
+    K: KeyManagementScheme,
+    K::Key: PartialEq + Debug,
+    K::KeyId: Clone + Ord + Debug,
+{
+    let updated: Vec<_> = ids.iter().step_by(2).cloned().collect();
+    for id in &updated {
+        kms.update(id.clone()).expect("update failed");
+    }
+
+    let before = derive_all(kms, ids);
+    let changes = kms.commit(OsRng).expect("commit failed");
+    let after = derive_all(kms, ids);
+
+    let mut changed = BTreeMap::new();
+    for (id, key) in changes {
+        let key = key.unwrap_or_else(|| panic!("commit revoked {id:?}, which was never revoked"));
+        let derived = kms.derive(id.clone()).expect("derive failed");
+        assert_eq!(derived, key, "commit returned a stale key for {id:?}");
+        assert!(
+            changed.insert(id.clone(), key).is_none(),
+            "commit returned {id:?} more than once"
+        );
+    }
+
+    for id in &updated {
+        assert!(changed.contains_key(id), "commit omitted updated {id:?}");
+    }
+
+    for ((id, before), after) in ids.iter().zip(&before).zip(&after) {
+        if !changed.contains_key(id) {
+            assert_eq!(before, after, "commit changed {id:?} without returning it");
+        } else if !updated.contains(id) {
+            assert_ne!(before, after, "commit returned unchanged {id:?}");
+        }
+    }
+
+    let changes = kms.commit(OsRng).expect("commit failed");
+    assert!(changes.is_empty(), "second commit changed keys");
+}
+
+/// Checks that `commit()` reports revoked keys as such, after which they are no longer derived.
+pub fn revoke_discards_key<K>(kms: &mut K, ids: &[K::KeyId])
+where
+    K: KeyManagementScheme,
+    K::Key: PartialEq + Debug,
+    K::KeyId: Clone + Ord + Debug,
+{
+    let revoked: Vec<_> = ids.iter().step_by(2).cloned().collect();
+    let before = derive_all(kms, ids);
+    for id in &revoked {
+        kms.revoke(id.clone()).expect("revoke failed");
+    }
+
+    let changes: BTreeMap<_, _> = kms
+        .commit(OsRng)
+        .expect("commit failed")
+        .into_iter()
+        .collect();
+    let after = derive_all(kms, ids);
+
+    for ((id, before), after) in ids.iter().zip(&before).zip(&after) {
+        match changes.get(id) {
+            Some(None) if revoked.contains(id) => {
+                assert_ne!(before, after, "derive of {id:?} returned its revoked key");
+            }
+            _ if revoked.contains(id) => panic!("commit did not report revoked {id:?}"),
+            Some(None) => panic!("commit revoked {id:?}, which was never revoked"),
+            Some(Some(key)) => assert_eq!(key, after, "commit returned a stale key for {id:?}"),
+            None => assert_eq!(before, after, "commit changed {id:?} without returning it"),
+        }
+    }
+}
+
+/// Checks that `commit_with()` visits the same keys that `commit()` would return.
+pub fn commit_with_visits_changed_keys<K>(kms: &mut K, ids: &[K::KeyId])
+where
+    K: KeyManagementScheme,
+    K::Key: PartialEq + Debug,
+    K::KeyId: Clone + Ord + Debug,
+{
+    let updated: Vec<_> = ids.iter().step_by(2).cloned().collect();
+    let revoked: Vec<_> = ids.iter().skip(1).step_by(2).cloned().collect();
+    derive_all(kms, ids);
+    for id in &updated {
+        kms.update(id.clone()).expect("update failed");
+    }
+    for id in &revoked {
+        kms.revoke(id.clone()).expect("revoke failed");
+    }
+
+    let mut visited = BTreeMap::new();
+    kms.commit_with(OsRng, |id, key| {
+        assert!(
+            visited.insert(id.clone(), key).is_none(),
+            "commit visited {id:?} more than once"
+        );
+    })
+    .expect("commit failed");
+
+    for id in &updated {
+        let derived = kms.derive(id.clone()).expect("derive failed");
+        match visited.get(id) {
+            Some(Some(key)) => assert_eq!(*key, derived, "commit visited a stale key for {id:?}"),
+            _ => panic!("commit did not visit updated {id:?}"),
+        }
+    }
+    for id in &revoked {
+        assert!(
+            matches!(visited.get(id), Some(None)),
+            "commit did not visit revoked {id:?}"
+        );
+    }
+
+    kms.commit_with(OsRng, |id, _| panic!("second commit visited {id:?}"))
+        .expect("commit failed");
+}
+
+/// Checks that `derive_many()` and `update_many()` agree with deriving and updating each key in
+/// turn, in any order and with repeats, before and after updates, revocations, and commits.
+pub fn derive_many_matches_derive<K>(kms: &mut K, ids: &[K::KeyId])
+where
+    K: KeyManagementScheme,
+    K::Key: PartialEq + Debug,
+    K::KeyId: Clone + Ord + Debug,
+{
+    let mut batch: Vec<_> = ids.iter().rev().cloned().collect();
+    batch.extend(ids.iter().step_by(3).cloned());
+    assert_derive_many(kms, &batch, "fresh");
+
+    let updated: Vec<_> = ids.iter().step_by(2).cloned().collect();
+    let keys = kms.update_many(updated.clone()).expect("update failed");
+    for (id, key) in updated.iter().zip(&keys) {
+        let derived = kms.derive(id.clone()).expect("derive failed");
+        assert_eq!(derived, *key, "update_many of {id:?} returned a stale key");
+    }
+    assert_derive_many(kms, &batch, "updated");
+    kms.commit(OsRng).expect("commit failed");
+    assert_derive_many(kms, &batch, "committed");
+
+    let (revoked, kept): (Vec<_>, Vec<_>) = ids
+        .iter()
+        .cloned()
+        .enumerate()
+        .partition(|(i, _)| i % 3 == 1);
+    for (_, id) in &revoked {
+        kms.revoke(id.clone()).expect("revoke failed");
+        assert_eq!(
+            kms.derive(id.clone()).is_ok(),
+            kms.derive_many([id.clone()]).is_ok(),
+            "derive_many and derive disagree on revoked {id:?}"
+        );
+    }
+    let kept: Vec<_> = kept.into_iter().map(|(_, id)| id).collect();
+    assert_derive_many(kms, &kept, "revoked");
+    kms.commit(OsRng).expect("commit failed");
+    assert_derive_many(kms, &batch, "recommitted");
+}
+
+/// Checks that `derive_many()` agrees with deriving each key in turn after ranges of keys are
+/// updated, each starting at one of the given ids and spanning up to 64 keys towards the next.
+///
+/// This is not run by [`check()`], as not every scheme supports updating ranges.
+pub fn derive_many_matches_derive_after_range<K>(kms: &mut K, ids: &[u64])
+where
+    K: UpdateRange,
+    K::Key: PartialEq + Debug,
+{
+    let mut sorted = ids.to_vec();
+    sorted.sort_unstable();
+    assert_derive_many(kms, ids, "fresh");
+
+    // Ranges are capped so that schemes updating each key in turn finish quickly.
+    for window in sorted.windows(2).step_by(2) {
+        let end = window[1].min(window[0].saturating_add(64));
+        kms.update_range(window[0]..end).expect("update failed");
+        assert_derive_many(kms, ids, "range-updated");
+    }
+    kms.commit(OsRng).expect("commit failed");
+    assert_derive_many(kms, ids, "committed");
+}
+
+/// Asserts that `derive_many()` of `ids` returns the keys derived one at a time.
+fn assert_derive_many<K>(kms: &mut K, ids: &[K::KeyId], state: &str)
+where
+    K: KeyManagementScheme,
+    K::Key: PartialEq + Debug,
+    K::KeyId: Clone,
+{
+    let many = kms.derive_many(ids.to_vec()).expect("derive failed");
+    assert_eq!(
+        many,
+        derive_all(kms, ids),
+        "derive_many disagrees with derive on {state} keys"
+    );
+}
+
+/// Checks that `abort()` discards every update and revocation since the last commit, leaving the
+/// committed keys as they were.
+///
+/// This is not run by [`check()`], as not every scheme supports aborting.
+pub fn abort_restores_keys<K>(kms: &mut K, ids: &[K::KeyId])
+where
+    K: Abort,
+    K::Key: PartialEq + Debug,
+    K::KeyId: Clone + Ord + Debug,
+{
+    let before = derive_all(kms, ids);
+    kms.commit(OsRng).expect("commit failed");
+
+    for (i, id) in ids.iter().enumerate() {
+        if i % 2 == 0 {
+            kms.update(id.clone()).expect("update failed");
+        } else {
+            kms.revoke(id.clone()).expect("revoke failed");
+        }
+    }
+
+    kms.abort();
+    assert_eq!(before, derive_all(kms, ids), "abort did not restore keys");
+
+    let changes = kms.commit(OsRng).expect("commit failed");
+    assert!(changes.is_empty(), "commit after abort changed keys");
+    assert_eq!(before, derive_all(kms, ids), "abort changed committed keys");
+}
+
+/// Derives the keys of all the given ids.
+fn derive_all<K>(kms: &mut K, ids: &[K::KeyId]) -> Vec<K::Key>
+where
+    K: KeyManagementScheme,
+    K::KeyId: Clone,
+{
+    ids.iter()
+        .map(|id| kms.derive(id.clone()).expect("derive failed"))
+        .collect()
+}
+
+/// Expands to a module of tests running each conformance check.
+///
+/// Takes the module name, an expression building a fresh scheme, and an array of key ids.
+#[macro_export]
+macro_rules! conformance_tests {
+    ($name:ident, $new:expr, $ids:expr) => {
+        mod $name {
+            use super::*;
+
+            #[test]
+            fn derive_is_stable() {
+                $crate::conformance::derive_is_stable(&mut ($new)(), &$ids);
+            }
+
+            #[test]
+            fn update_changes_key() {
+                $crate::conformance::update_changes_key(&mut ($new)(), &$ids);
+            }
+
+            #[test]
+            fn commit_returns_changed_keys() {
+                $crate::conformance::commit_returns_changed_keys(&mut ($new)(), &$ids);
+            }
+
+            #[test]
+            fn revoke_discards_key() {
+                $crate::conformance::revoke_discards_key(&mut ($new)(), &$ids);
+            }
+
+            #[test]
+            fn commit_with_visits_changed_keys() {
+                $crate::conformance::commit_with_visits_changed_keys(&mut ($new)(), &$ids);
+            }
+
+            #[test]
+            fn derive_many_matches_derive() {
+                $crate::conformance::derive_many_matches_derive(&mut ($new)(), &$ids);
+            }
+        }
+    };
+}
@@ -0,0 +1,276 @@
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use rand::{CryptoRng, RngCore};
+
+use crate::{
+    codec::{invalid, read_u64, write_u64},
+    format, Abort, FileStorage, KeyManagementScheme, KeyProvider, KmsError, Persist, Sealed,
+    Storage, Versioned,
+};
+
+/// Marks the scheme identifiers of epoch-counted schemes in the [`format`] container.
+const EPOCH_SCHEME: u16 = 0x4000;
+
+/// A counter that can only move forward, kept somewhere an attacker cannot roll back.
+pub trait MonotonicCounter {
+    /// Reads the current value of the counter.
+    fn read(&mut self) -> Result<u64, KmsError>;
+
+    /// Raises the counter to `value`, leaving it as is if it is already at least `value`.
+    fn advance(&mut self, value: u64) -> Result<(), KmsError>;
+}
+
+/// A monotonic counter kept in a file.
+///
+/// This is only a stand-in for a trusted counter, such as one kept by a TPM or a remote service,
+/// as anyone who can restore old state can restore an old counter file too.
+pub struct FileCounter {
+    storage: FileStorage,
+    name: String,
+}
+
+impl FileCounter {
+    /// Opens the counter kept in the file at `path`, which reads as zero until first advanced.
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self, KmsError> {
+        let path = path.into();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| invalid("counter path has no file name"))?
+            .to_owned();
+        // A bare file name has an empty parent, which is the working directory.
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        Ok(Self {
+            storage: FileStorage::new(dir)?,
+            name,
+        })
+    }
+}
+
+impl MonotonicCounter for FileCounter {
+    fn read(&mut self) -> Result<u64, KmsError> {
+        match self.storage.read(&self.name)? {
+            Some(bytes) => read_u64(&mut &bytes[..]),
+            None => Ok(0),
+        }
+    }
+
+    fn advance(&mut self, value: u64) -> Result<(), KmsError> {
+        if value > self.read()? {
+            self.storage.replace(&self.name, &value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// A scheme whose persisted state records how many times it has been committed.
+///
+/// Checking the epoch of loaded state against a [`MonotonicCounter`] detects an old copy of the
+/// state being restored, along with every key that has been revoked since. The epoch is only as
+/// trustworthy as the state holding it, so the state should also be sealed, as by
+/// [`Sealed<Epoch<K>>`](crate::Sealed).
+///
+/// The counter must be advanced with [`Epoch::record()`] once the state is durably persisted, and
+/// state must be loaded with [`Epoch::load_verified()`] or, when sealed,
+/// [`Sealed::load_verified()`]. The plain loaders of `Persist`, `Versioned` and `Sealed` do not
+/// check the epoch.
+///
+/// An `Epoch` cannot be nested in another, as both would have the same identifier in the
+/// [`format`](mod@crate::format) container, so persisting one fails to compile.
+pub struct Epoch<K> {
+    inner: K,
+    epoch: u64,
+}
+
+impl<K> Epoch<K> {
+    /// Counts the commits of `inner`, starting from epoch zero.
+    pub fn new(inner: K) -> Self {
+        Self { inner, epoch: 0 }
+    }
+
+    /// Returns the number of commits so far.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns the wrapped scheme.
+    pub fn into_inner(self) -> K {
+        self.inner
+    }
+
+    /// Checks that loaded state is not older than `counter`, failing with
+    /// [`KmsError::Rollback`] if it is.
+    ///
+    /// State newer than the counter was persisted without its epoch being recorded, so the
+    /// counter is advanced to catch up.
+    pub fn verify(&self, counter: &mut impl MonotonicCounter) -> Result<(), KmsError> {
+        let trusted = counter.read()?;
+        if self.epoch < trusted {
+            return Err(KmsError::Rollback {
+                epoch: self.epoch,
+                counter: trusted,
+            });
+        }
+        counter.advance(self.epoch)
+    }
+
+    /// Advances `counter` to the current epoch, once state persisted at this epoch is durable.
+    pub fn record(&self, counter: &mut impl MonotonicCounter) -> Result<(), KmsError> {
+        counter.advance(self.epoch)
+    }
+}
+
+impl<K> Epoch<K>
+where
+    K: Versioned,
+    K::Error: From<KmsError>,
+{
+    /// Reads state written by `format::encode()` and checks it against `counter`.
+    pub fn load_verified(
+        reader: impl Read,
+        counter: &mut impl MonotonicCounter,
+    ) -> Result<Self, K::Error> {
+        let kms = format::decode::<Self>(reader)?;
+        kms.verify(counter)?;
+        Ok(kms)
+    }
+
+    /// Loads state from the named blob in `storage`, as by `Versioned::load_from()`, and checks
+    /// it against `counter`.
+    pub fn load_verified_from(
+        storage: &mut impl Storage,
+        name: &str,
+        counter: &mut impl MonotonicCounter,
+    ) -> Result<Self, K::Error> {
+        let kms = Self::load_from(storage, name)?;
+        kms.verify(counter)?;
+        Ok(kms)
+    }
+}
+
+impl<K> Sealed<Epoch<K>>
+where
+    K: Versioned,
+    K::Error: From<KmsError>,
+{
+    /// Opens sealed state as by `Sealed::load()` and checks it against `counter`.
+    pub fn load_verified(
+        provider: &impl KeyProvider,
+        reader: impl Read,
+        counter: &mut impl MonotonicCounter,
+    ) -> Result<Self, K::Error> {
+        let kms = Self::load(provider, reader)?;
+        kms.inner().verify(counter)?;
+        Ok(kms)
+    }
+
+    /// Opens the sealed state in the named blob in `storage`, as by `Sealed::load_from()`, and
+    /// checks it against `counter`.
+    pub fn load_verified_from(
+        provider: &impl KeyProvider,
+        storage: &mut impl Storage,
+        name: &str,
+        counter: &mut impl MonotonicCounter,
+    ) -> Result<Self, K::Error> {
+        let kms = Self::load_from(provider, storage, name)?;
+        kms.inner().verify(counter)?;
+        Ok(kms)
+    }
+}
+
+impl<K: KeyManagementScheme> KeyManagementScheme for Epoch<K> {
+    type Key = K::Key;
+    type KeyId = K::KeyId;
+    type Error = K::Error;
+
+    fn derive(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.inner.derive(key)
+    }
+
+    fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.inner.update(key)
+    }
+
+    fn derive_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        self.inner.derive_many(keys)
+    }
+
+    fn update_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        self.inner.update_many(keys)
+    }
+
+    fn revoke(&mut self, key: Self::KeyId) -> Result<(), Self::Error> {
+        self.inner.revoke(key)
+    }
+
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Option<Self::Key>)>, Self::Error> {
+        let changes = self.inner.commit(rng)?;
+        self.epoch += 1;
+        Ok(changes)
+    }
+
+    fn commit_with(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+        visit: impl FnMut(Self::KeyId, Option<Self::Key>),
+    ) -> Result<(), Self::Error> {
+        self.inner.commit_with(rng, visit)?;
+        self.epoch += 1;
+        Ok(())
+    }
+}
+
+impl<K: Abort> Abort for Epoch<K> {
+    fn abort(&mut self) {
+        self.inner.abort();
+    }
+}
+
+impl<K> Persist for Epoch<K>
+where
+    K: Persist,
+    K::Error: From<KmsError>,
+{
+    fn persist(&mut self, mut writer: impl Write) -> Result<(), Self::Error> {
+        write_u64(&mut writer, self.epoch)?;
+        self.inner.persist(writer)
+    }
+
+    fn load(mut reader: impl Read) -> Result<Self, Self::Error> {
+        let epoch = read_u64(&mut reader)?;
+        Ok(Self {
+            inner: K::load(reader)?,
+            epoch,
+        })
+    }
+}
+
+impl<K> Versioned for Epoch<K>
+where
+    K: Versioned,
+    K::Error: From<KmsError>,
+{
+    const SCHEME_ID: u16 = {
+        assert!(
+            K::SCHEME_ID & EPOCH_SCHEME == 0,
+            "epoch-counted schemes cannot be nested"
+        );
+        EPOCH_SCHEME | K::SCHEME_ID
+    };
+    const KEY_LEN: u16 = K::KEY_LEN;
+}
@@ -0,0 +1,81 @@
+use core::fmt::{self, Display};
+use std::{error::Error, io};
+
+/// The error type shared by the bundled schemes.
+#[derive(Debug)]
+pub enum KmsError {
+    /// The key id is not known to the scheme.
+    UnknownKey,
+    /// The key has been revoked.
+    Revoked,
+    /// The storage backend failed.
+    Io(io::Error),
+    /// Persisted state is malformed or failed an integrity check.
+    Corrupted(String),
+    /// Persisted state is older than the trusted counter, as if an old copy had been restored.
+    Rollback {
+        /// The epoch recorded in the state.
+        epoch: u64,
+        /// The value of the trusted counter.
+        counter: u64,
+    },
+    /// The RNG failed to produce randomness.
+    Rng(rand::Error),
+    /// A custom backend failed.
+    Backend(Box<dyn Error + Send + Sync>),
+}
+
+impl KmsError {
+    /// Wraps an error from a custom backend.
+    pub fn backend(err: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self::Backend(err.into())
+    }
+}
+
+impl Display for KmsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownKey => write!(f, "unknown key id"),
+            Self::Revoked => write!(f, "key has been revoked"),
+            Self::Io(err) => write!(f, "storage I/O failed: {err}"),
+            Self::Corrupted(reason) => write!(f, "corrupted state: {reason}"),
+            Self::Rollback { epoch, counter } => {
+                write!(
+                    f,
+                    "state from epoch {epoch} rolled back behind counter {counter}"
+                )
+            }
+            Self::Rng(err) => write!(f, "RNG failed: {err}"),
+            Self::Backend(err) => write!(f, "backend failed: {err}"),
+        }
+    }
+}
+
+impl Error for KmsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Rng(err) => Some(err),
+            Self::Backend(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KmsError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<rand::Error> for KmsError {
+    fn from(err: rand::Error) -> Self {
+        Self::Rng(err)
+    }
+}
+
+impl From<Box<dyn Error + Send + Sync>> for KmsError {
+    fn from(err: Box<dyn Error + Send + Sync>) -> Self {
+        Self::Backend(err)
+    }
+}
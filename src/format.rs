@@ -0,0 +1,178 @@
+//! A versioned, self-describing container for persisted scheme state.
+//!
+//! Version 1 of the container is laid out as follows, with integers in little-endian:
+//!
+//! | Field          | Bytes | Contents                                 |
+//! |----------------|-------|------------------------------------------|
+//! | magic          | 4     | [`MAGIC`]                                |
+//! | version        | 2     | [`VERSION`]                              |
+//! | scheme         | 2     | [`Versioned::SCHEME_ID`]                 |
+//! | key length     | 2     | [`Versioned::KEY_LEN`]                   |
+//! | payload length | 8     |                                          |
+//! | payload        |       | the state written by `Persist::persist()` |
+//! | checksum       | 32    | SHA-256 of every preceding byte          |
+//!
+//! Version 0 is the bare payload, as persisted before the container existed. It carries no header
+//! or checksum, so [`decode()`] rejects it, and it can only be read with [`decode_v0()`] by callers
+//! that know their state predates the container.
+
+use std::io::{self, Read, Write};
+
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+use crate::{codec::invalid, Khf, KmsError, Kwt, MemoryKms, Persist, Storage, KEY_LEN};
+
+/// The magic number opening every container.
+pub const MAGIC: [u8; 4] = *b"\x89KMS";
+
+/// The current version of the container format.
+pub const VERSION: u16 = 1;
+
+const HEADER_LEN: usize = 18;
+const CHECKSUM_LEN: usize = 32;
+
+/// A persistable scheme with an identifier in the container format.
+///
+/// Identifiers below `0x8000` are reserved for the bundled schemes, and identifiers with bit
+/// `0x4000` set are reserved for [`Epoch`](crate::Epoch)s wrapping the scheme with that bit clear.
+pub trait Versioned: Persist {
+    /// The identifier of the scheme.
+    const SCHEME_ID: u16;
+    /// The length in bytes of the scheme's keys.
+    const KEY_LEN: u16;
+
+    /// Persists the state of `self` as of the last `commit()` to the named blob in `storage`,
+    /// atomically replacing what was persisted there before.
+    fn persist_to(&mut self, storage: &mut impl Storage, name: &str) -> Result<(), Self::Error>
+    where
+        Self::Error: From<KmsError>,
+    {
+        let mut bytes = Vec::new();
+        encode(self, &mut bytes)?;
+        let replaced = storage.replace(name, &bytes);
+        bytes.zeroize();
+        Ok(replaced?)
+    }
+
+    /// Loads a scheme from the named blob in `storage`, previously written by `persist_to()`.
+    fn load_from(storage: &mut impl Storage, name: &str) -> Result<Self, Self::Error>
+    where
+        Self::Error: From<KmsError>,
+    {
+        let bytes = storage
+            .read(name)?
+            .ok_or_else(|| KmsError::Io(io::ErrorKind::NotFound.into()))?;
+        decode(&bytes[..])
+    }
+}
+
+impl Versioned for MemoryKms<u64> {
+    const SCHEME_ID: u16 = 1;
+    const KEY_LEN: u16 = KEY_LEN as u16;
+}
+
+impl Versioned for Khf {
+    const SCHEME_ID: u16 = 2;
+    const KEY_LEN: u16 = KEY_LEN as u16;
+}
+
+impl Versioned for Kwt {
+    const SCHEME_ID: u16 = 3;
+    const KEY_LEN: u16 = KEY_LEN as u16;
+}
+
+/// Writes the state of `kms` as of the last `commit()` in the current container format.
+pub fn encode<K>(kms: &mut K, mut writer: impl Write) -> Result<(), K::Error>
+where
+    K: Versioned,
+    K::Error: From<KmsError>,
+{
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&K::SCHEME_ID.to_le_bytes());
+    bytes.extend_from_slice(&K::KEY_LEN.to_le_bytes());
+    bytes.extend_from_slice(&[0; 8]);
+
+    kms.persist(&mut bytes)?;
+    let len = (bytes.len() - HEADER_LEN) as u64;
+    bytes[HEADER_LEN - 8..HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+
+    let checksum = Sha256::digest(&bytes);
+    bytes.extend_from_slice(&checksum);
+
+    let written = writer.write_all(&bytes).and_then(|_| writer.flush());
+    bytes.zeroize();
+    Ok(written.map_err(KmsError::from)?)
+}
+
+/// Reads state written in a supported version of the container format.
+///
+/// State persisted before the container existed is rejected, and must be read with
+/// [`decode_v0()`] instead.
+pub fn decode<K>(mut reader: impl Read) -> Result<K, K::Error>
+where
+    K: Versioned,
+    K::Error: From<KmsError>,
+{
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).map_err(KmsError::from)?;
+
+    let decoded = payload::<K>(&bytes)
+        .map_err(K::Error::from)
+        .and_then(K::load);
+    bytes.zeroize();
+    decoded
+}
+
+/// Reads state persisted as a bare payload, before the container existed.
+///
+/// Nothing identifies the scheme or protects the state from corruption, so this should only be
+/// used to migrate state known to predate the container, which [`encode()`] then upgrades.
+pub fn decode_v0<K>(mut reader: impl Read) -> Result<K, K::Error>
+where
+    K: Versioned,
+    K::Error: From<KmsError>,
+{
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).map_err(KmsError::from)?;
+
+    let decoded = K::load(&bytes[..]);
+    bytes.zeroize();
+    decoded
+}
+
+/// Validates a container of the current version and returns its payload.
+fn payload<K: Versioned>(bytes: &[u8]) -> Result<&[u8], KmsError> {
+    if !bytes.starts_with(&MAGIC) {
+        return Err(invalid("missing magic number"));
+    }
+    if bytes.len() < HEADER_LEN + CHECKSUM_LEN {
+        return Err(invalid("truncated header"));
+    }
+
+    let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+    if *Sha256::digest(body) != *checksum {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    let field = |at: usize| u16::from_le_bytes([body[at], body[at + 1]]);
+    match field(4) {
+        VERSION => {}
+        _ => return Err(invalid("unsupported format version")),
+    }
+    if field(6) != K::SCHEME_ID {
+        return Err(invalid("state belongs to another scheme"));
+    }
+    if field(8) != K::KEY_LEN {
+        return Err(invalid("state has another key length"));
+    }
+
+    let len = u64::from_le_bytes(body[10..HEADER_LEN].try_into().expect("8 bytes"));
+    if len != (body.len() - HEADER_LEN) as u64 {
+        return Err(invalid("payload length mismatch"));
+    }
+
+    Ok(&body[HEADER_LEN..])
+}
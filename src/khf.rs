@@ -0,0 +1,478 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+    ops::Range,
+    slice,
+};
+
+use rand::{
+    rngs::{OsRng, StdRng},
+    CryptoRng, RngCore,
+};
+use sha2::{compress256, digest::generic_array::GenericArray};
+use zeroize::Zeroizing;
+
+use crate::{
+    codec::{invalid, read_key, read_u64, write_u64},
+    reseed, Abort, Key, KeyManagementScheme, KmsError, Persist, SharedDerive, UpdateRange,
+};
+
+/// A keyed hash forest.
+///
+/// Keys are the leaves of a forest of identically shaped trees. Each child key is the keyed hash of
+/// its parent key and its index, so only the root of each tree needs to be stored. Updating a leaf
+/// fragments its tree into the siblings along its path and a fresh leaf key. Committing
+/// consolidates every fragmented tree, and every tree with a revoked leaf, under a new root,
+/// re-keying all of its leaves.
+pub struct Khf {
+    fanouts: Vec<u64>,
+    spans: Vec<u64>,
+    rng: StdRng,
+    roots: BTreeMap<u64, Key>,
+    fragments: BTreeMap<u64, Fragment>,
+    revoked: BTreeSet<u64>,
+}
+
+/// A subtree split off from a fragmented tree.
+struct Fragment {
+    depth: usize,
+    key: Key,
+}
+
+impl Khf {
+    /// Creates a forest of trees with the given fanouts, listed from the roots down to the leaves.
+    ///
+    /// Panics if `fanouts` is empty, contains a zero, or if the number of leaves per tree
+    /// overflows a `u64`.
+    ///
+    /// If the number of leaves per tree does not divide 2^64, the leaves of the last tree, which
+    /// is cut short by the end of the `u64` range, are unknown to the forest.
+    ///
+    /// Also panics if `rng` fails to seed the forest's RNG; see [`Khf::try_new`].
+    pub fn new(rng: impl RngCore + CryptoRng, fanouts: &[u64]) -> Self {
+        Self::try_new(rng, fanouts).expect("RNG failed")
+    }
+
+    /// Like [`Khf::new`], but returns an error instead of panicking if `rng` fails.
+    pub fn try_new(rng: impl RngCore + CryptoRng, fanouts: &[u64]) -> Result<Self, KmsError> {
+        Ok(Self {
+            fanouts: fanouts.to_vec(),
+            spans: spans(fanouts).expect("invalid fanouts"),
+            rng: reseed(rng)?,
+            roots: BTreeMap::new(),
+            fragments: BTreeMap::new(),
+            revoked: BTreeSet::new(),
+        })
+    }
+
+    /// The number of leaves covered by each tree.
+    fn span(&self) -> u64 {
+        self.spans[0]
+    }
+
+    /// Recreates a forest from persisted state.
+    fn restore(fanouts: Vec<u64>, roots: BTreeMap<u64, Key>) -> Result<Self, KmsError> {
+        let spans = spans(&fanouts).ok_or_else(|| invalid("invalid fanouts"))?;
+        if roots.keys().any(|tree| !whole(*tree, spans[0])) {
+            return Err(invalid("tree out of range"));
+        }
+
+        Ok(Self {
+            fanouts,
+            spans,
+            rng: reseed(OsRng)?,
+            roots,
+            fragments: BTreeMap::new(),
+            revoked: BTreeSet::new(),
+        })
+    }
+
+    /// Fails for leaves of the last tree if it is cut short by the end of the `u64` range.
+    fn check(&self, leaf: u64) -> Result<(), KmsError> {
+        match whole(leaf / self.span(), self.span()) {
+            true => Ok(()),
+            false => Err(KmsError::UnknownKey),
+        }
+    }
+
+    /// The depth at which leaves sit.
+    fn leaf_depth(&self) -> usize {
+        self.fanouts.len()
+    }
+
+    /// Returns the first leaf, depth, and key of the subtree root covering `leaf`.
+    fn cover(&mut self, leaf: u64) -> (u64, usize, Key) {
+        if let Some(cover) = self.try_cover(leaf) {
+            return cover;
+        }
+
+        let tree = leaf / self.span();
+        let rng = &mut self.rng;
+        let root = self
+            .roots
+            .entry(tree)
+            .or_insert_with(|| Key::random(rng))
+            .clone();
+        (tree * self.span(), 0, root)
+    }
+
+    /// Returns the root of the fragment or tree covering `leaf`, or `None` if its tree has no root
+    /// yet.
+    fn try_cover(&self, leaf: u64) -> Option<(u64, usize, Key)> {
+        if let Some((&start, fragment)) = self.fragments.range(..=leaf).next_back() {
+            if leaf - start < self.spans[fragment.depth] {
+                return Some((start, fragment.depth, fragment.key.clone()));
+            }
+        }
+
+        let tree = leaf / self.span();
+        let root = self.roots.get(&tree)?;
+        Some((tree * self.span(), 0, root.clone()))
+    }
+
+    /// Extends `path`, a chain of subtree roots ending in one that covers `leaf`, down to `leaf`.
+    fn descend(&self, path: &mut Vec<(u64, usize, Key)>, leaf: u64) {
+        let (mut start, depth, mut key) = path.last().cloned().expect("path has a root");
+        for depth in depth..self.leaf_depth() {
+            let child = (leaf - start) / self.spans[depth + 1];
+            start += child * self.spans[depth + 1];
+            key = hash(&key, child);
+            path.push((start, depth + 1, key.clone()));
+        }
+    }
+
+    /// Fragments the forest so that the node at `depth` whose first leaf is `start` becomes a
+    /// fragment, and gives it a fresh key, which is returned.
+    fn replace(&mut self, start: u64, depth: usize) -> Key {
+        let (mut first, cover, mut root) = self.cover(start);
+
+        if cover > depth {
+            // The node is already split into finer fragments, all of which it replaces.
+            let last = start + (self.spans[depth] - 1);
+            let inner: Vec<u64> = self
+                .fragments
+                .range(start..=last)
+                .map(|(s, _)| *s)
+                .collect();
+            for first in inner {
+                self.fragments.remove(&first);
+            }
+        } else {
+            self.fragments.remove(&first);
+
+            // Split off every sibling along the path down to the node.
+            for depth in cover..depth {
+                let span = self.spans[depth + 1];
+                let path = (start - first) / span;
+
+                for child in (0..self.fanouts[depth]).filter(|child| *child != path) {
+                    let fragment = Fragment {
+                        depth: depth + 1,
+                        key: hash(&root, child),
+                    };
+                    self.fragments.insert(first + child * span, fragment);
+                }
+
+                first += path * span;
+                root = hash(&root, path);
+            }
+        }
+
+        let new = Key::random(&mut self.rng);
+        let fragment = Fragment {
+            depth,
+            key: new.clone(),
+        };
+        self.fragments.insert(start, fragment);
+        new
+    }
+
+    /// Visits every leaf of the subtree root at `depth` whose first leaf is `start`.
+    fn leaves(&self, start: u64, depth: usize, key: Key, visit: &mut impl FnMut(u64, Key)) {
+        if depth == self.leaf_depth() {
+            visit(start, key);
+            return;
+        }
+
+        for child in 0..self.fanouts[depth] {
+            let start = start + child * self.spans[depth + 1];
+            self.leaves(start, depth + 1, hash(&key, child), visit);
+        }
+    }
+}
+
+impl KeyManagementScheme for Khf {
+    type Key = Key;
+    type KeyId = u64;
+    type Error = KmsError;
+
+    fn derive(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.check(key)?;
+        if self.revoked.contains(&key) {
+            return Err(KmsError::Revoked);
+        }
+
+        let mut path = vec![self.cover(key)];
+        self.descend(&mut path, key);
+        Ok(path.pop().expect("path has a leaf").2)
+    }
+
+    fn derive_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        // Keys along the last derived path are reused by the next leaf that shares them.
+        let mut path = Vec::new();
+        keys.into_iter()
+            .map(|leaf| {
+                self.check(leaf)?;
+                if self.revoked.contains(&leaf) {
+                    return Err(KmsError::Revoked);
+                }
+
+                let shared = path.iter().rposition(|(start, depth, _)| {
+                    leaf >= *start && leaf - start < self.spans[*depth]
+                });
+                match shared {
+                    Some(shared) => path.truncate(shared + 1),
+                    None => path = vec![self.cover(leaf)],
+                }
+
+                self.descend(&mut path, leaf);
+                Ok(path.last().expect("path has a leaf").2.clone())
+            })
+            .collect()
+    }
+
+    fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.check(key)?;
+        self.revoked.remove(&key);
+        Ok(self.replace(key, self.leaf_depth()))
+    }
+
+    fn revoke(&mut self, key: Self::KeyId) -> Result<(), Self::Error> {
+        self.check(key)?;
+        self.revoked.insert(key);
+        Ok(())
+    }
+
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Option<Self::Key>)>, Self::Error> {
+        let mut changes = Vec::new();
+        self.commit_with(rng, |leaf, key| changes.push((leaf, key)))?;
+        Ok(changes)
+    }
+
+    fn commit_with(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+        mut visit: impl FnMut(Self::KeyId, Option<Self::Key>),
+    ) -> Result<(), Self::Error> {
+        let mut rng = reseed(rng)?;
+        let trees: BTreeSet<u64> = self
+            .fragments
+            .keys()
+            .chain(&self.revoked)
+            .map(|leaf| leaf / self.span())
+            .collect();
+
+        for tree in trees {
+            let root = Key::random(&mut rng);
+            self.roots.insert(tree, root.clone());
+            self.leaves(tree * self.span(), 0, root, &mut |leaf, key| {
+                let key = (!self.revoked.contains(&leaf)).then_some(key);
+                visit(leaf, key)
+            });
+        }
+
+        self.fragments.clear();
+        self.revoked.clear();
+        self.rng = reseed(&mut rng)?;
+
+        Ok(())
+    }
+}
+
+impl Abort for Khf {
+    fn abort(&mut self) {
+        self.fragments.clear();
+        self.revoked.clear();
+    }
+}
+
+impl SharedDerive for Khf {
+    fn try_derive(&self, key: Self::KeyId) -> Option<Result<Self::Key, Self::Error>> {
+        if let Err(err) = self.check(key) {
+            return Some(Err(err));
+        }
+        if self.revoked.contains(&key) {
+            return Some(Err(KmsError::Revoked));
+        }
+
+        let mut path = vec![self.try_cover(key)?];
+        self.descend(&mut path, key);
+        Some(Ok(path.pop().expect("path has a leaf").2))
+    }
+}
+
+impl Persist for Khf {
+    fn persist(&mut self, mut writer: impl Write) -> Result<(), Self::Error> {
+        write_u64(&mut writer, self.fanouts.len() as u64)?;
+        for fanout in &self.fanouts {
+            write_u64(&mut writer, *fanout)?;
+        }
+
+        write_u64(&mut writer, self.roots.len() as u64)?;
+        for (tree, root) in &self.roots {
+            write_u64(&mut writer, *tree)?;
+            writer.write_all(root.as_bytes())?;
+        }
+        Ok(writer.flush()?)
+    }
+
+    fn load(mut reader: impl Read) -> Result<Self, Self::Error> {
+        let fanouts = (0..read_u64(&mut reader)?)
+            .map(|_| read_u64(&mut reader))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut roots = BTreeMap::new();
+        for _ in 0..read_u64(&mut reader)? {
+            let tree = read_u64(&mut reader)?;
+            roots.insert(tree, read_key(&mut reader)?);
+        }
+
+        Self::restore(fanouts, roots)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Khf {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("Khf", 2)?;
+        state.serialize_field("fanouts", &self.fanouts)?;
+        state.serialize_field("roots", &self.roots)?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Khf {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename = "Khf")]
+        struct State {
+            fanouts: Vec<u64>,
+            roots: BTreeMap<u64, Key>,
+        }
+
+        let State { fanouts, roots } = State::deserialize(deserializer)?;
+        Self::restore(fanouts, roots).map_err(serde::de::Error::custom)
+    }
+}
+
+impl UpdateRange for Khf {
+    fn update_range(&mut self, keys: Range<u64>) -> Result<(), Self::Error> {
+        if let Some(last) = keys.end.checked_sub(1).filter(|last| *last >= keys.start) {
+            self.check(last)?;
+        }
+        self.revoked.retain(|leaf| !keys.contains(leaf));
+
+        let mut leaf = keys.start;
+        while leaf < keys.end {
+            // Replace the largest aligned subtree starting at this leaf that fits in the range.
+            let mut depth = self.leaf_depth();
+            while depth > 0
+                && leaf.is_multiple_of(self.spans[depth - 1])
+                && keys.end - leaf >= self.spans[depth - 1]
+            {
+                depth -= 1;
+            }
+
+            self.replace(leaf, depth);
+
+            match leaf.checked_add(self.spans[depth]) {
+                Some(next) => leaf = next,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Computes the number of leaves covered by a node at each depth, or `None` if `fanouts` is empty,
+/// contains a zero, or covers more leaves per tree than fit in a `u64`.
+fn spans(fanouts: &[u64]) -> Option<Vec<u64>> {
+    if fanouts.is_empty() || fanouts.contains(&0) {
+        return None;
+    }
+
+    let mut spans = vec![1u64];
+    for fanout in fanouts.iter().rev() {
+        spans.insert(0, spans[0].checked_mul(*fanout)?);
+    }
+    Some(spans)
+}
+
+/// Returns whether every leaf of `tree` fits in a `u64`.
+fn whole(tree: u64, span: u64) -> bool {
+    tree.checked_mul(span)
+        .and_then(|first| first.checked_add(span - 1))
+        .is_some()
+}
+
+/// The initial SHA-256 state.
+const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Derives the key of the child at index `child` from its parent's key.
+///
+/// This is HMAC-SHA256 of the index under the parent key, computed block by block so that every
+/// buffer and hash state that depends on the key is zeroized afterwards.
+fn hash(key: &Key, child: u64) -> Key {
+    let mut block = Zeroizing::new([0; 64]);
+    let mut inner = Zeroizing::new(IV);
+    let mut outer = Zeroizing::new(IV);
+
+    // H((K ^ ipad) || index), padded to a second block holding 64 + 8 bytes of message.
+    pad(&mut block, key, 0x36);
+    compress(&mut inner, &block);
+    block.fill(0);
+    block[..8].copy_from_slice(&child.to_le_bytes());
+    block[8] = 0x80;
+    block[56..].copy_from_slice(&((64 + 8) * 8u64).to_be_bytes());
+    compress(&mut inner, &block);
+
+    // H((K ^ opad) || inner), padded to a second block holding 64 + 32 bytes of message.
+    pad(&mut block, key, 0x5c);
+    compress(&mut outer, &block);
+    block.fill(0);
+    for (bytes, word) in block.chunks_exact_mut(4).zip(inner.iter()) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    block[32] = 0x80;
+    block[56..].copy_from_slice(&((64 + 32) * 8u64).to_be_bytes());
+    compress(&mut outer, &block);
+
+    let mut derived = Key::default();
+    for (bytes, word) in derived.as_mut_bytes().chunks_exact_mut(4).zip(outer.iter()) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    derived
+}
+
+/// Fills `block` with `key` XORed with the HMAC pad byte `pad`.
+fn pad(block: &mut [u8; 64], key: &Key, pad: u8) {
+    block.fill(pad);
+    for (byte, key) in block.iter_mut().zip(key.as_bytes()) {
+        *byte ^= key;
+    }
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8; 64]) {
+    compress256(state, slice::from_ref(GenericArray::from_slice(block)));
+}
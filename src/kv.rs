@@ -0,0 +1,354 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroizing;
+
+use crate::{
+    blocks::{open, seal},
+    codec::{invalid, read_u64, write_u64},
+    BlockStore, Key, KmsError, Reencryptor, Storage, Versioned,
+};
+
+/// The length of the id and length preceding each record in the log.
+const HEADER_LEN: u64 = 16;
+
+/// An append-only log of records, each holding an encrypted entry or a tombstone for an id.
+struct Log {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    /// The offset and length of the latest record holding each live entry.
+    latest: BTreeMap<u64, (u64, u64)>,
+}
+
+/// The records of each id in a log, oldest first, with tombstones having a length of zero.
+type History = BTreeMap<u64, Vec<(u64, u64)>>;
+
+impl Log {
+    /// Opens the log at `path`, dropping a record torn by a crash from its end.
+    fn open(path: PathBuf) -> Result<(Self, History), KmsError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let size = file.metadata()?.len();
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let mut reader = &bytes[..];
+        let mut history = History::new();
+        let mut len = 0;
+        while reader.len() as u64 >= HEADER_LEN {
+            let id = read_u64(&mut reader)?;
+            let body = read_u64(&mut reader)?;
+            if (reader.len() as u64) < body {
+                break;
+            }
+            history
+                .entry(id)
+                .or_default()
+                .push((len + HEADER_LEN, body));
+            reader = &reader[body as usize..];
+            len += HEADER_LEN + body;
+        }
+        if len < size {
+            file.set_len(len)?;
+            file.sync_data()?;
+        }
+
+        let latest = history
+            .iter()
+            .filter_map(|(id, records)| records.last().map(|record| (*id, *record)))
+            .filter(|(_, (_, body))| *body != 0)
+            .collect();
+        let log = Self {
+            path,
+            file,
+            len,
+            latest,
+        };
+        Ok((log, history))
+    }
+
+    /// Appends a record without syncing it, returning the offset of its body.
+    fn append(&mut self, id: u64, body: &[u8]) -> Result<u64, KmsError> {
+        let mut record = Vec::with_capacity(HEADER_LEN as usize + body.len());
+        write_u64(&mut record, id)?;
+        write_u64(&mut record, body.len() as u64)?;
+        record.extend_from_slice(body);
+
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(&record)?;
+        let offset = self.len + HEADER_LEN;
+        self.len += record.len() as u64;
+        Ok(offset)
+    }
+
+    fn read_at(&mut self, (offset, len): (u64, u64)) -> Result<Vec<u8>, KmsError> {
+        let mut body = vec![0; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut body)?;
+        Ok(body)
+    }
+
+    /// Replaces the log with one holding only the latest record of each live entry.
+    fn compact(&mut self) -> Result<(), KmsError> {
+        let tmp = self.path.with_extension("compact");
+        let mut file = File::create(&tmp)?;
+        let mut latest = BTreeMap::new();
+        let mut len = 0;
+        for (id, record) in self.latest.clone() {
+            let body = self.read_at(record)?;
+            let mut bytes = Vec::new();
+            write_u64(&mut bytes, id)?;
+            write_u64(&mut bytes, body.len() as u64)?;
+            bytes.extend_from_slice(&body);
+            file.write_all(&bytes)?;
+
+            latest.insert(id, (len + HEADER_LEN, record.1));
+            len += bytes.len() as u64;
+        }
+        file.sync_all()?;
+
+        fs::rename(&tmp, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.len = len;
+        self.latest = latest;
+        Ok(())
+    }
+}
+
+impl BlockStore for Log {
+    fn blocks(&mut self) -> Result<Vec<u64>, KmsError> {
+        Ok(self.latest.keys().copied().collect())
+    }
+
+    fn read(&mut self, block: u64) -> Result<Vec<u8>, KmsError> {
+        let record = *self.latest.get(&block).ok_or(KmsError::UnknownKey)?;
+        self.read_at(record)
+    }
+
+    fn write(&mut self, block: u64, bytes: &[u8]) -> Result<(), KmsError> {
+        let offset = self.append(block, bytes)?;
+        self.file.sync_data()?;
+        self.latest.insert(block, (offset, bytes.len() as u64));
+        Ok(())
+    }
+
+    fn encrypt(&self, key: &Key, block: u64, plaintext: &[u8]) -> Result<Vec<u8>, KmsError> {
+        seal(key, block, plaintext)
+    }
+
+    fn decrypt(&self, key: &Key, block: u64, ciphertext: &[u8]) -> Result<Vec<u8>, KmsError> {
+        open(key, block, ciphertext)
+    }
+}
+
+/// Encodes an entry as the length of its key, its key, and its value.
+fn encode(name: &[u8], value: &[u8]) -> Result<Zeroizing<Vec<u8>>, KmsError> {
+    let mut bytes = Zeroizing::new(Vec::with_capacity(8 + name.len() + value.len()));
+    write_u64(&mut *bytes, name.len() as u64)?;
+    bytes.extend_from_slice(name);
+    bytes.extend_from_slice(value);
+    Ok(bytes)
+}
+
+/// Splits an entry encoded by `encode()` into its key and value.
+fn decode(bytes: &[u8]) -> Result<(&[u8], &[u8]), KmsError> {
+    let mut reader = bytes;
+    let len = read_u64(&mut reader)?;
+    if (reader.len() as u64) < len {
+        return Err(invalid("truncated entry"));
+    }
+    Ok(reader.split_at(len as usize))
+}
+
+/// A key-value store whose deleted and overwritten values are securely deleted.
+///
+/// Each entry is assigned an id, and its key and value are encrypted together under the key that
+/// id derives. Entries live in an append-only log, and changes are buffered in memory until
+/// `flush()`, which appends them to the log and commits the scheme: overwriting an entry updates
+/// its key and deleting it revokes its key, so the old records can no longer be decrypted.
+/// Records whose keys change in the commit are re-encrypted by a [`Reencryptor`] sharing the
+/// scheme's storage, and `compact()` drops the records that can no longer be read from the log.
+///
+/// Changes since the last flush are lost on a crash, as are the values written by a flush that
+/// crashes before committing, though the deletions it appended still take effect.
+pub struct KvStore<K, S> {
+    kms: K,
+    log: Log,
+    reencryptor: Reencryptor<S>,
+    ids: HashMap<Vec<u8>, u64>,
+    next: u64,
+    pending: BTreeMap<Vec<u8>, Option<Zeroizing<Vec<u8>>>>,
+    revoked: Vec<u64>,
+}
+
+impl<K, S> KvStore<K, S>
+where
+    K: Versioned<KeyId = u64, Key = Key>,
+    K::Error: From<KmsError>,
+    S: Storage,
+{
+    /// Creates an empty store with its log at `path` and a fresh scheme, persisting the scheme to
+    /// the blob of `storage` named `name`.
+    pub fn create(
+        mut kms: K,
+        mut storage: S,
+        name: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, K::Error> {
+        File::create(path.as_ref()).map_err(KmsError::from)?;
+        kms.persist_to(&mut storage, name)?;
+        let (log, _) = Log::open(path.as_ref().into())?;
+        Ok(Self {
+            kms,
+            log,
+            reencryptor: Reencryptor::new(storage, name),
+            ids: HashMap::new(),
+            next: 0,
+            pending: BTreeMap::new(),
+            revoked: Vec::new(),
+        })
+    }
+
+    /// Opens a store previously created with its log at `path`, finishing any interrupted flush.
+    pub fn open(mut storage: S, name: &str, path: impl AsRef<Path>) -> Result<Self, K::Error> {
+        let mut kms = K::load_from(&mut storage, name)?;
+        let mut reencryptor = Reencryptor::new(storage, name);
+        let (mut log, history) = Log::open(path.as_ref().into())?;
+        reencryptor.resume(&mut kms, &mut log)?;
+
+        // The records a resumed flush re-encrypts are appended after those in `history`.
+        let resumed = mem::take(&mut log.latest);
+        let mut ids = HashMap::new();
+        let mut revoked = Vec::new();
+        for (&id, records) in &history {
+            // Records appended by a flush that never committed are under keys that were lost, as
+            // are those of an entry whose deletion was committed.
+            let key = kms.derive(id)?;
+            let appended = resumed
+                .get(&id)
+                .filter(|record| Some(*record) != records.last());
+            let mut found = None;
+            for &record in appended.into_iter().chain(records.iter().rev()) {
+                if record.1 == 0 {
+                    continue;
+                }
+                let body = log.read_at(record)?;
+                if let Ok(entry) = open(&key, id, &body).map(Zeroizing::new) {
+                    found = Some((record, entry));
+                    break;
+                }
+            }
+            let Some((record, entry)) = found else {
+                continue;
+            };
+
+            if records.last().is_some_and(|(_, body)| *body == 0) {
+                // A flush that appended a tombstone crashed before committing its revocation.
+                revoked.push(id);
+            } else {
+                ids.insert(decode(&entry)?.0.to_vec(), id);
+                log.latest.insert(id, record);
+            }
+        }
+
+        Ok(Self {
+            kms,
+            log,
+            reencryptor,
+            ids,
+            next: history.keys().next_back().map_or(0, |id| id + 1),
+            pending: BTreeMap::new(),
+            revoked,
+        })
+    }
+
+    /// Returns the value of the entry with the given key, if any.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, K::Error> {
+        if let Some(value) = self.pending.get(key) {
+            return Ok(value.as_ref().map(|value| value.to_vec()));
+        }
+        let Some(&id) = self.ids.get(key) else {
+            return Ok(None);
+        };
+
+        let body = self.log.read(id)?;
+        let entry = Zeroizing::new(open(&self.kms.derive(id)?, id, &body)?);
+        let (name, value) = decode(&entry)?;
+        if name != key {
+            return Err(invalid("entry does not match its key").into());
+        }
+        Ok(Some(value.to_vec()))
+    }
+
+    /// Sets the value of the entry with the given key as of the next flush.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.pending
+            .insert(key.to_vec(), Some(Zeroizing::new(value.to_vec())));
+    }
+
+    /// Deletes the entry with the given key as of the next flush.
+    pub fn delete(&mut self, key: &[u8]) {
+        self.pending.insert(key.to_vec(), None);
+    }
+
+    /// Appends the buffered changes to the log and commits the scheme, securely deleting the
+    /// values they replace.
+    ///
+    /// The changes stay buffered until the commit succeeds, so if this fails, the next flush
+    /// applies them again.
+    pub fn flush(&mut self, rng: impl RngCore + CryptoRng) -> Result<(), K::Error> {
+        for (name, value) in &self.pending {
+            match value {
+                Some(value) => {
+                    let id = *self.ids.entry(name.clone()).or_insert_with(|| {
+                        self.next += 1;
+                        self.next - 1
+                    });
+                    let key = self.kms.update(id)?;
+                    let body = seal(&key, id, &encode(name, value)?)?;
+                    let offset = self.log.append(id, &body)?;
+                    self.log.latest.insert(id, (offset, body.len() as u64));
+                }
+                None => {
+                    if let Some(&id) = self.ids.get(name) {
+                        self.log.append(id, &[])?;
+                        self.log.latest.remove(&id);
+                        self.ids.remove(name);
+                        self.revoked.push(id);
+                    }
+                }
+            }
+        }
+
+        // A failed commit may have rolled the scheme back, so every revocation is made again.
+        for &id in &self.revoked {
+            self.kms.revoke(id)?;
+        }
+        self.log.file.sync_data().map_err(KmsError::from)?;
+
+        self.reencryptor.commit(&mut self.kms, &mut self.log, rng)?;
+        self.pending.clear();
+        self.revoked.clear();
+        Ok(())
+    }
+
+    /// Flushes the store, then rewrites the log with only the latest record of each entry.
+    pub fn compact(&mut self, rng: impl RngCore + CryptoRng) -> Result<(), K::Error> {
+        self.flush(rng)?;
+        Ok(self.log.compact()?)
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+    mem,
+};
+
+use aes_kw::KekAes256;
+use rand::{
+    rngs::{OsRng, StdRng},
+    CryptoRng, RngCore,
+};
+
+use crate::{
+    codec::{invalid, read_array, read_key, read_u64, write_u64},
+    reseed, Abort, Key, KeyManagementScheme, KmsError, Persist, SharedDerive, UpdateRange, KEY_LEN,
+};
+
+/// A key wrapped under its parent's key.
+type Wrapped = [u8; KEY_LEN + aes_kw::IV_LEN];
+
+/// A position in the tree, as a depth and an index within that depth.
+type Pos = (usize, u64);
+
+/// A key-wrapping tree.
+///
+/// Keys are the leaves of a tree in which every node's key is wrapped under its parent's key, so
+/// only the root key is kept in the clear. Updating or revoking a leaf gives it a fresh key or
+/// drops it, and marks its path to the root dirty. Committing re-keys only the dirty nodes and
+/// re-wraps their children, which leaves the keys of every other leaf untouched.
+pub struct Kwt {
+    bits: u32,
+    height: usize,
+    rng: StdRng,
+    root: Key,
+    nodes: BTreeMap<Pos, Wrapped>,
+    dirty: BTreeSet<Pos>,
+    pending: BTreeMap<u64, Option<Key>>,
+}
+
+impl Kwt {
+    /// Creates a tree with the given fanout, deep enough to cover every `u64` key id.
+    ///
+    /// Panics if `fanout` is not a power of two greater than one, or if `rng` fails; see
+    /// [`Kwt::try_new`].
+    pub fn new(rng: impl RngCore + CryptoRng, fanout: u64) -> Self {
+        Self::try_new(rng, fanout).expect("RNG failed")
+    }
+
+    /// Like [`Kwt::new`], but returns an error instead of panicking if `rng` fails.
+    pub fn try_new(rng: impl RngCore + CryptoRng, fanout: u64) -> Result<Self, KmsError> {
+        assert!(
+            fanout > 1 && fanout.is_power_of_two(),
+            "fanout must be a power of two greater than one"
+        );
+
+        let mut rng = reseed(rng)?;
+        let root = Key::random(&mut rng);
+        Ok(Self::with_root(rng, fanout, root))
+    }
+
+    /// Creates a tree with the given fanout and root key, and no nodes.
+    fn with_root(rng: StdRng, fanout: u64, root: Key) -> Self {
+        let bits = fanout.trailing_zeros();
+        Self {
+            bits,
+            height: u64::BITS.div_ceil(bits) as usize,
+            rng,
+            root,
+            nodes: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Recreates a tree with no nodes from persisted state.
+    fn restore(fanout: u64, root: Key) -> Result<Self, KmsError> {
+        if fanout < 2 || !fanout.is_power_of_two() {
+            return Err(invalid("invalid fanout"));
+        }
+        Ok(Self::with_root(reseed(OsRng)?, fanout, root))
+    }
+
+    /// Adds a committed node from persisted state.
+    fn restore_node(&mut self, depth: u64, index: u64, wrapped: Wrapped) -> Result<(), KmsError> {
+        if depth == 0 || depth > self.height as u64 {
+            return Err(invalid("node out of range"));
+        }
+        self.nodes.insert((depth as usize, index), wrapped);
+        Ok(())
+    }
+
+    /// Returns the position of the ancestor of `leaf` at the given depth.
+    fn ancestor(&self, leaf: u64, depth: usize) -> Pos {
+        let shift = self.bits * (self.height - depth) as u32;
+        (depth, leaf.checked_shr(shift).unwrap_or(0))
+    }
+
+    /// Extends `path`, a chain of unwrapped ancestors of `leaf` starting at the root, down to
+    /// `leaf`, creating any missing nodes along the way.
+    fn descend(&mut self, path: &mut Vec<(Pos, Key)>, leaf: u64) -> Result<(), KmsError> {
+        for depth in path.len()..=self.height {
+            let pos = self.ancestor(leaf, depth);
+            let parent = &path.last().expect("path has a root").1;
+            let child = match self.nodes.get(&pos) {
+                Some(wrapped) => unwrap(parent, wrapped)?,
+                None => {
+                    let child = Key::random(&mut self.rng);
+                    self.nodes.insert(pos, wrap(parent, &child));
+                    child
+                }
+            };
+            path.push((pos, child));
+        }
+        Ok(())
+    }
+
+    /// Marks the path from `leaf` up to the root dirty.
+    fn mark(&mut self, leaf: u64) {
+        for depth in 0..self.height {
+            let pos = self.ancestor(leaf, depth);
+            self.dirty.insert(pos);
+        }
+    }
+
+    /// Returns the range of positions that children of `pos` may occupy.
+    fn children(&self, (depth, index): Pos) -> (Pos, Pos) {
+        let first = index << self.bits;
+        let last = first.saturating_add((1 << self.bits) - 1);
+        ((depth + 1, first), (depth + 1, last))
+    }
+}
+
+impl KeyManagementScheme for Kwt {
+    type Key = Key;
+    type KeyId = u64;
+    type Error = KmsError;
+
+    fn derive(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        match self.pending.get(&key) {
+            Some(Some(updated)) => return Ok(updated.clone()),
+            Some(None) => return Err(KmsError::Revoked),
+            None => {}
+        }
+
+        let mut path = vec![((0, 0), self.root.clone())];
+        self.descend(&mut path, key)?;
+        Ok(path.pop().expect("path has a leaf").1)
+    }
+
+    fn derive_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        // Keys along the last derived path are reused by the next leaf that shares them.
+        let mut path = vec![((0, 0), self.root.clone())];
+        keys.into_iter()
+            .map(|leaf| {
+                match self.pending.get(&leaf) {
+                    Some(Some(updated)) => return Ok(updated.clone()),
+                    Some(None) => return Err(KmsError::Revoked),
+                    None => {}
+                }
+
+                let shared = path
+                    .iter()
+                    .rposition(|(pos, _)| *pos == self.ancestor(leaf, pos.0))
+                    .expect("every leaf shares the root");
+                path.truncate(shared + 1);
+
+                self.descend(&mut path, leaf)?;
+                Ok(path.last().expect("path has a leaf").1.clone())
+            })
+            .collect()
+    }
+
+    fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.mark(key);
+        let new = Key::random(&mut self.rng);
+        self.pending.insert(key, Some(new.clone()));
+        Ok(new)
+    }
+
+    fn revoke(&mut self, key: Self::KeyId) -> Result<(), Self::Error> {
+        self.mark(key);
+        self.pending.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Option<Self::Key>)>, Self::Error> {
+        let mut changes = Vec::with_capacity(self.pending.len());
+        self.commit_with(rng, |leaf, key| changes.push((leaf, key)))?;
+        Ok(changes)
+    }
+
+    fn commit_with(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+        mut visit: impl FnMut(Self::KeyId, Option<Self::Key>),
+    ) -> Result<(), Self::Error> {
+        let mut rng = reseed(rng)?;
+
+        // Recover the current keys of dirty nodes top-down, before anything is re-wrapped.
+        let mut old: BTreeMap<Pos, Option<Key>> = BTreeMap::new();
+        for &pos in &self.dirty {
+            let key = match pos {
+                (0, _) => Some(self.root.clone()),
+                (depth, index) => {
+                    let parent = (depth - 1, index >> self.bits);
+                    old[&parent]
+                        .as_ref()
+                        .zip(self.nodes.get(&pos))
+                        .map(|(parent, wrapped)| unwrap(parent, wrapped))
+                        .transpose()?
+                }
+            };
+            old.insert(pos, key);
+        }
+
+        let new: BTreeMap<Pos, Key> = self
+            .dirty
+            .iter()
+            .map(|&pos| (pos, Key::random(&mut rng)))
+            .collect();
+
+        // Re-wrap the clean children of dirty nodes under their parent's new key. Nothing is
+        // modified until every unwrap has succeeded.
+        let mut rewrapped = Vec::new();
+        for (&pos, key) in &new {
+            let Some(old) = &old[&pos] else {
+                continue;
+            };
+
+            let (first, last) = self.children(pos);
+            for (&child, wrapped) in self.nodes.range(first..=last) {
+                let leaf = child.0 == self.height && self.pending.contains_key(&child.1);
+                if !leaf && !new.contains_key(&child) {
+                    rewrapped.push((child, wrap(key, &unwrap(old, wrapped)?)));
+                }
+            }
+        }
+
+        // Wrap the dirty nodes and updated leaves themselves, and drop revoked leaves.
+        let parent = |(depth, index): Pos| &new[&(depth - 1, index >> self.bits)];
+        for (&pos, key) in new.iter().filter(|(pos, _)| pos.0 > 0) {
+            rewrapped.push((pos, wrap(parent(pos), key)));
+        }
+        for (&leaf, key) in &self.pending {
+            let pos = (self.height, leaf);
+            match key {
+                Some(key) => rewrapped.push((pos, wrap(parent(pos), key))),
+                None => {
+                    self.nodes.remove(&pos);
+                }
+            }
+        }
+
+        self.nodes.extend(rewrapped);
+
+        if let Some(root) = new.get(&(0, 0)) {
+            self.root = root.clone();
+        }
+
+        self.dirty.clear();
+        self.rng = reseed(&mut rng)?;
+
+        for (leaf, key) in mem::take(&mut self.pending) {
+            visit(leaf, key);
+        }
+        Ok(())
+    }
+}
+
+impl UpdateRange for Kwt {}
+
+impl Abort for Kwt {
+    fn abort(&mut self) {
+        self.dirty.clear();
+        self.pending.clear();
+    }
+}
+
+impl SharedDerive for Kwt {
+    fn try_derive(&self, key: Self::KeyId) -> Option<Result<Self::Key, Self::Error>> {
+        match self.pending.get(&key) {
+            Some(Some(updated)) => return Some(Ok(updated.clone())),
+            Some(None) => return Some(Err(KmsError::Revoked)),
+            None => {}
+        }
+
+        let mut parent = self.root.clone();
+        for depth in 1..=self.height {
+            let wrapped = self.nodes.get(&self.ancestor(key, depth))?;
+            parent = match unwrap(&parent, wrapped) {
+                Ok(child) => child,
+                Err(err) => return Some(Err(err)),
+            };
+        }
+        Some(Ok(parent))
+    }
+}
+
+impl Persist for Kwt {
+    fn persist(&mut self, mut writer: impl Write) -> Result<(), Self::Error> {
+        write_u64(&mut writer, 1 << self.bits)?;
+        writer.write_all(self.root.as_bytes())?;
+
+        write_u64(&mut writer, self.nodes.len() as u64)?;
+        for ((depth, index), wrapped) in &self.nodes {
+            write_u64(&mut writer, *depth as u64)?;
+            write_u64(&mut writer, *index)?;
+            writer.write_all(wrapped)?;
+        }
+        Ok(writer.flush()?)
+    }
+
+    fn load(mut reader: impl Read) -> Result<Self, Self::Error> {
+        let fanout = read_u64(&mut reader)?;
+        let mut kwt = Self::restore(fanout, read_key(&mut reader)?)?;
+        for _ in 0..read_u64(&mut reader)? {
+            let depth = read_u64(&mut reader)?;
+            let index = read_u64(&mut reader)?;
+            kwt.restore_node(depth, index, read_array(&mut reader)?)?;
+        }
+
+        Ok(kwt)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Kwt {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let nodes: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|((depth, index), wrapped)| (*depth as u64, *index, &wrapped[..]))
+            .collect();
+
+        let mut state = serializer.serialize_struct("Kwt", 3)?;
+        state.serialize_field("fanout", &(1u64 << self.bits))?;
+        state.serialize_field("root", &self.root)?;
+        state.serialize_field("nodes", &nodes)?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Kwt {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        #[derive(serde::Deserialize)]
+        #[serde(rename = "Kwt")]
+        struct State {
+            fanout: u64,
+            root: Key,
+            nodes: Vec<(u64, u64, Vec<u8>)>,
+        }
+
+        let State {
+            fanout,
+            root,
+            nodes,
+        } = State::deserialize(deserializer)?;
+
+        let mut kwt = Self::restore(fanout, root).map_err(D::Error::custom)?;
+        for (depth, index, wrapped) in nodes {
+            let wrapped = wrapped
+                .try_into()
+                .map_err(|_| D::Error::custom("wrapped key has the wrong length"))?;
+            kwt.restore_node(depth, index, wrapped)
+                .map_err(D::Error::custom)?;
+        }
+
+        Ok(kwt)
+    }
+}
+
+/// Wraps `child` under `parent`.
+fn wrap(parent: &Key, child: &Key) -> Wrapped {
+    let mut wrapped = [0; KEY_LEN + aes_kw::IV_LEN];
+    KekAes256::new(parent.as_bytes().into())
+        .wrap(child.as_bytes(), &mut wrapped)
+        .expect("wrapped buffer has room for the integrity check");
+    wrapped
+}
+
+/// Unwraps a key wrapped under `parent`, failing if it was wrapped under another key.
+fn unwrap(parent: &Key, wrapped: &Wrapped) -> Result<Key, KmsError> {
+    let mut child = Key::default();
+    KekAes256::new(parent.as_bytes().into())
+        .unwrap(wrapped, child.as_mut_bytes())
+        .map_err(|_| invalid("key failed to unwrap"))?;
+    Ok(child)
+}
@@ -1,6 +1,55 @@
 use core::fmt::Debug;
+use std::{
+    io::{Read, Write},
+    ops::Range,
+};
 
-use rand::{CryptoRng, RngCore};
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
+
+mod asynchronous;
+mod blocks;
+mod cached;
+mod codec;
+#[cfg(feature = "testing")]
+pub mod conformance;
+mod epoch;
+mod error;
+pub mod format;
+mod khf;
+mod kv;
+mod kwt;
+mod memory;
+mod reencrypt;
+mod sealed;
+mod secret;
+mod shared;
+mod staged;
+mod storage;
+mod wal;
+
+pub use asynchronous::{AsyncKeyManagementScheme, Blocking};
+pub use blocks::{Device, EncryptedBlockStore};
+pub use cached::{CacheStats, Cached};
+pub use epoch::{Epoch, FileCounter, MonotonicCounter};
+pub use error::KmsError;
+pub use format::Versioned;
+pub use khf::Khf;
+pub use kv::KvStore;
+pub use kwt::Kwt;
+pub use memory::MemoryKms;
+pub use reencrypt::{BlockStore, Reencryptor};
+pub use sealed::{Kdf, KeyProvider, MasterKey, Passphrase, Sealed};
+pub use secret::SecretKey;
+pub use shared::{SharedDerive, SharedKms};
+pub use staged::{Abort, Staged};
+pub use storage::{FileStorage, MemStorage, Storage};
+pub use wal::{Medium, Wal};
+
+/// The length in bytes of the keys produced by the bundled schemes.
+pub const KEY_LEN: usize = 32;
+
+/// The key type produced by the bundled schemes.
+pub type Key = SecretKey<KEY_LEN>;
 
 /// A trait describing the basic functionality of a key management scheme.
 pub trait KeyManagementScheme {
@@ -21,10 +70,85 @@ pub trait KeyManagementScheme {
     /// Revocation of the old key is only guaranteed after calling `commit()`.
     fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error>;
 
-    /// Commits any deferred key updates, guaranteeing their revocation from `self`,
-    /// assuming that all keys which persisted `self` in the past are securely deleted.
+    /// Derive the keys corresponding to each of the given `KeyId`s, in order.
+    ///
+    /// Schemes may override this to share work between neighbouring `KeyId`s.
+    fn derive_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        keys.into_iter().map(|key| self.derive(key)).collect()
+    }
+
+    /// Update the keys corresponding to each of the given `KeyId`s, in order.
+    ///
+    /// Schemes may override this to share work between neighbouring `KeyId`s.
+    fn update_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        keys.into_iter().map(|key| self.update(key)).collect()
+    }
+
+    /// Revoke the key corresponding to the given `KeyId`, without replacing it.
+    ///
+    /// Revocation is only guaranteed after calling `commit()`, after which deriving the `KeyId`
+    /// yields a fresh key unrelated to the revoked one. Until then, schemes may refuse to derive
+    /// the `KeyId`. Updating the `KeyId` before then cancels the revocation.
+    fn revoke(&mut self, key: Self::KeyId) -> Result<(), Self::Error>;
+
+    /// Commits any deferred key updates and revocations, guaranteeing their revocation from
+    /// `self`, assuming that all keys which persisted `self` in the past are securely deleted.
+    ///
+    /// Returns every `KeyId` whose key changed, paired with its new key, or `None` if it was
+    /// revoked.
+    #[allow(clippy::type_complexity)]
     fn commit(
         &mut self,
         rng: impl RngCore + CryptoRng,
-    ) -> Result<Vec<(Self::KeyId, Self::Key)>, Self::Error>;
+    ) -> Result<Vec<(Self::KeyId, Option<Self::Key>)>, Self::Error>;
+
+    /// Commits as by `commit()`, but passes every `KeyId` whose key changed to `visit` as it is
+    /// produced, paired with its new key, or `None` if it was revoked.
+    ///
+    /// Schemes may override this to avoid collecting every changed key at once.
+    fn commit_with(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+        mut visit: impl FnMut(Self::KeyId, Option<Self::Key>),
+    ) -> Result<(), Self::Error> {
+        for (key, new) in self.commit(rng)? {
+            visit(key, new);
+        }
+        Ok(())
+    }
+}
+
+/// A trait for key management schemes whose `KeyId`s are contiguous, such as block numbers.
+pub trait UpdateRange: KeyManagementScheme<KeyId = u64> {
+    /// Update the keys corresponding to every `KeyId` in the given range.
+    ///
+    /// Revocation of the old keys is only guaranteed after calling `commit()`.
+    fn update_range(&mut self, keys: Range<u64>) -> Result<(), Self::Error> {
+        keys.into_iter()
+            .try_for_each(|key| self.update(key).map(drop))
+    }
+}
+
+/// A trait for key management schemes whose state can be saved and restored.
+pub trait Persist: KeyManagementScheme + Sized {
+    /// Persists the state of `self` as of the last `commit()` to the given writer.
+    ///
+    /// Updates that have not been committed yet are not persisted.
+    fn persist(&mut self, writer: impl Write) -> Result<(), Self::Error>;
+
+    /// Loads a scheme from state previously written by `persist()`.
+    fn load(reader: impl Read) -> Result<Self, Self::Error>;
+}
+
+/// Seeds a fresh `StdRng` from the given RNG.
+pub(crate) fn reseed(mut rng: impl RngCore + CryptoRng) -> Result<StdRng, rand::Error> {
+    let mut seed = <StdRng as SeedableRng>::Seed::default();
+    rng.try_fill_bytes(&mut seed)?;
+    Ok(StdRng::from_seed(seed))
 }
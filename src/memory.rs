@@ -0,0 +1,177 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    mem,
+};
+
+use rand::{
+    rngs::{OsRng, StdRng},
+    CryptoRng, RngCore,
+};
+
+use crate::{
+    codec::{read_key, read_u64, write_u64},
+    reseed, Abort, Key, KeyManagementScheme, KmsError, Persist, SharedDerive, UpdateRange,
+};
+
+/// A reference key management scheme that keeps every key in a map.
+///
+/// Keys are generated lazily from an internal RNG the first time they are derived. Updates and
+/// revocations are staged separately and only replace or remove the committed keys on `commit()`.
+pub struct MemoryKms<I = u64> {
+    rng: StdRng,
+    keys: BTreeMap<I, Key>,
+    pending: BTreeMap<I, Option<Key>>,
+}
+
+impl<I: Ord> MemoryKms<I> {
+    /// Creates an empty scheme whose internal RNG is seeded from `rng`.
+    ///
+    /// Panics if `rng` fails; see [`MemoryKms::try_new`].
+    pub fn new(rng: impl RngCore + CryptoRng) -> Self {
+        Self::try_new(rng).expect("RNG failed")
+    }
+
+    /// Like [`MemoryKms::new`], but returns an error instead of panicking if `rng` fails.
+    pub fn try_new(rng: impl RngCore + CryptoRng) -> Result<Self, KmsError> {
+        Ok(Self {
+            rng: reseed(rng)?,
+            keys: BTreeMap::new(),
+            pending: BTreeMap::new(),
+        })
+    }
+}
+
+impl<I: Ord + Clone> KeyManagementScheme for MemoryKms<I> {
+    type Key = Key;
+    type KeyId = I;
+    type Error = KmsError;
+
+    fn derive(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        match self.pending.get(&key) {
+            Some(Some(updated)) => return Ok(updated.clone()),
+            Some(None) => return Err(KmsError::Revoked),
+            None => {}
+        }
+
+        let rng = &mut self.rng;
+        Ok(self
+            .keys
+            .entry(key)
+            .or_insert_with(|| Key::random(rng))
+            .clone())
+    }
+
+    fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        let new = Key::random(&mut self.rng);
+        self.pending.insert(key, Some(new.clone()));
+        Ok(new)
+    }
+
+    fn revoke(&mut self, key: Self::KeyId) -> Result<(), Self::Error> {
+        self.pending.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Option<Self::Key>)>, Self::Error> {
+        let mut changes = Vec::with_capacity(self.pending.len());
+        self.commit_with(rng, |id, key| changes.push((id, key)))?;
+        Ok(changes)
+    }
+
+    fn commit_with(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+        mut visit: impl FnMut(Self::KeyId, Option<Self::Key>),
+    ) -> Result<(), Self::Error> {
+        // Keys drawn before this commit should not be reproducible from the RNG state.
+        self.rng = reseed(rng)?;
+
+        for (id, key) in mem::take(&mut self.pending) {
+            match &key {
+                Some(key) => self.keys.insert(id.clone(), key.clone()),
+                None => self.keys.remove(&id),
+            };
+            visit(id, key);
+        }
+        Ok(())
+    }
+}
+
+impl<I: Ord + Clone> SharedDerive for MemoryKms<I> {
+    fn try_derive(&self, key: Self::KeyId) -> Option<Result<Self::Key, Self::Error>> {
+        match self.pending.get(&key) {
+            Some(Some(updated)) => Some(Ok(updated.clone())),
+            Some(None) => Some(Err(KmsError::Revoked)),
+            None => self.keys.get(&key).cloned().map(Ok),
+        }
+    }
+}
+
+impl<I: Ord + Clone> Abort for MemoryKms<I> {
+    fn abort(&mut self) {
+        self.pending.clear();
+    }
+}
+
+impl UpdateRange for MemoryKms<u64> {}
+
+impl Persist for MemoryKms<u64> {
+    fn persist(&mut self, mut writer: impl Write) -> Result<(), Self::Error> {
+        write_u64(&mut writer, self.keys.len() as u64)?;
+        for (id, key) in &self.keys {
+            write_u64(&mut writer, *id)?;
+            writer.write_all(key.as_bytes())?;
+        }
+        Ok(writer.flush()?)
+    }
+
+    fn load(mut reader: impl Read) -> Result<Self, Self::Error> {
+        let mut keys = BTreeMap::new();
+        for _ in 0..read_u64(&mut reader)? {
+            let id = read_u64(&mut reader)?;
+            keys.insert(id, read_key(&mut reader)?);
+        }
+
+        Self::restore(keys)
+    }
+}
+
+impl MemoryKms<u64> {
+    /// Recreates a scheme from persisted keys.
+    fn restore(keys: BTreeMap<u64, Key>) -> Result<Self, KmsError> {
+        Ok(Self {
+            rng: reseed(OsRng)?,
+            keys,
+            pending: BTreeMap::new(),
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for MemoryKms<u64> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("MemoryKms", 1)?;
+        state.serialize_field("keys", &self.keys)?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for MemoryKms<u64> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename = "MemoryKms")]
+        struct State {
+            keys: BTreeMap<u64, Key>,
+        }
+
+        let State { keys } = State::deserialize(deserializer)?;
+        Self::restore(keys).map_err(serde::de::Error::custom)
+    }
+}
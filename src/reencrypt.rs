@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+use crate::{
+    codec::{invalid, read_key, read_u64, write_u64},
+    format, Key, KmsError, Storage, Versioned,
+};
+
+/// A store of blocks, each encrypted under the key whose `KeyId` is the block's number.
+pub trait BlockStore {
+    /// Returns the number of every block holding data.
+    fn blocks(&mut self) -> Result<Vec<u64>, KmsError>;
+
+    /// Reads the encrypted contents of a block.
+    fn read(&mut self, block: u64) -> Result<Vec<u8>, KmsError>;
+
+    /// Durably replaces the encrypted contents of a block.
+    fn write(&mut self, block: u64, bytes: &[u8]) -> Result<(), KmsError>;
+
+    /// Encrypts the contents of a block under `key`.
+    fn encrypt(&self, key: &Key, block: u64, plaintext: &[u8]) -> Result<Vec<u8>, KmsError>;
+
+    /// Decrypts the contents of a block, failing if they were not encrypted under `key`.
+    fn decrypt(&self, key: &Key, block: u64, ciphertext: &[u8]) -> Result<Vec<u8>, KmsError>;
+}
+
+/// A block whose key changed in a commit.
+struct Entry {
+    block: u64,
+    old: Key,
+    new: Key,
+}
+
+/// What a commit needs to re-encrypt its blocks, recorded before the committed scheme is
+/// persisted.
+struct Journal {
+    /// The state of the scheme as of the previous commit, as written by `format::encode()`.
+    state: Vec<u8>,
+    /// Every live block whose key changed, in the order the commit reported them.
+    entries: Vec<Entry>,
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        self.state.zeroize();
+    }
+}
+
+/// Commits a scheme and re-encrypts every block whose key changed, resuming after a crash.
+///
+/// A commit goes through the following steps, using three blobs in the given storage:
+///
+/// 1. The state of the scheme as of its last commit is persisted to `{name}`, unless it is there
+///    already.
+/// 2. The current key of every block is derived, and the scheme is committed.
+/// 3. The old and new keys of every block the commit re-keyed are recorded in `{name}.journal`,
+///    along with the state from step 1.
+/// 4. The committed scheme is persisted to `{name}`.
+/// 5. Every journaled block is re-encrypted from its old key to its new one, tracking progress in
+///    `{name}.cursor`.
+/// 6. The journal and cursor are discarded.
+///
+/// Blocks revoked since the last commit cannot be derived before it, and are left as they are
+/// once the commit reports them as revoked. Any other block whose key cannot be derived fails the
+/// commit.
+///
+/// After a crash, the scheme is loaded from `{name}` and handed to [`Reencryptor::resume()`],
+/// which finishes the re-encryption if the commit was persisted, and discards it otherwise. The
+/// journal holds old keys in the clear until it is discarded, so the storage must be trusted to
+/// the same extent as the persisted scheme.
+pub struct Reencryptor<S> {
+    storage: S,
+    name: String,
+}
+
+impl<S: Storage> Reencryptor<S> {
+    /// Keeps the scheme state and re-encryption progress in the blobs of `storage` named after
+    /// `name`.
+    pub fn new(storage: S, name: impl Into<String>) -> Self {
+        Self {
+            storage,
+            name: name.into(),
+        }
+    }
+
+    /// Borrows the storage, such as to load the scheme after a crash.
+    pub fn storage(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    /// Commits `kms`, persists it, and re-encrypts every block in `blocks` whose key changed.
+    ///
+    /// A re-encryption left unfinished by an earlier failure is finished first. If the commit
+    /// fails before it is persisted, `kms` is restored to its state as of its last commit, losing
+    /// the updates since as a crash would.
+    ///
+    /// Returns the number of re-encrypted blocks.
+    pub fn commit<K, B>(
+        &mut self,
+        kms: &mut K,
+        blocks: &mut B,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<u64, K::Error>
+    where
+        K: Versioned<KeyId = u64, Key = Key>,
+        K::Error: From<KmsError>,
+        B: BlockStore,
+    {
+        let resumed = self.resume(kms, blocks)?;
+
+        let mut state = Vec::new();
+        format::encode(kms, &mut state)?;
+        if !self.holds(&state)? {
+            self.storage.replace(&self.name, &state)?;
+        }
+
+        // Whether a block that fails to derive was revoked is only known once the commit reports
+        // it, so the failure is held until then.
+        let mut old = BTreeMap::new();
+        for block in blocks.blocks()? {
+            old.insert(block, kms.derive(block));
+        }
+
+        let mut entries = Vec::new();
+        let mut failed = None;
+        let committed = kms.commit_with(rng, |block, new| match (old.remove(&block), new) {
+            (Some(Ok(old)), Some(new)) if old != new => entries.push(Entry { block, old, new }),
+            (Some(Err(err)), Some(_)) => drop(failed.get_or_insert(err)),
+            _ => {}
+        });
+        let failed = failed.or_else(|| old.into_values().find_map(Result::err));
+
+        let journal = Journal { state, entries };
+        let persisted = committed
+            .and_then(|()| failed.map_or(Ok(()), Err))
+            .and_then(|()| Ok(self.write_journal(&journal)?))
+            .and_then(|()| kms.persist_to(&mut self.storage, &self.name));
+        if let Err(err) = persisted {
+            // The journal is discarded by the next commit or resume, as the state never changed.
+            *kms = format::decode::<K>(&journal.state[..])?;
+            return Err(err);
+        }
+
+        Ok(resumed + self.rewrite(&journal, 0, blocks)?)
+    }
+
+    /// Finishes a commit interrupted by a crash, given the scheme as loaded from its persisted
+    /// state.
+    ///
+    /// Returns the number of re-encrypted blocks, which is zero if no commit was interrupted or
+    /// if it was never persisted.
+    pub fn resume<K, B>(&mut self, kms: &mut K, blocks: &mut B) -> Result<u64, K::Error>
+    where
+        K: Versioned<KeyId = u64, Key = Key>,
+        K::Error: From<KmsError>,
+        B: BlockStore,
+    {
+        let Some(journal) = self.read_journal()? else {
+            return Ok(0);
+        };
+
+        // Blocks are only re-encrypted once the commit is persisted, so if the persisted state is
+        // the one from before the commit, the commit was lost.
+        if self.holds(&journal.state)? {
+            self.discard()?;
+            return Ok(0);
+        }
+
+        let cursor = match self.storage.read(&self.cursor())? {
+            Some(bytes) => read_u64(&mut &bytes[..])?,
+            None => 0,
+        };
+        if let (0, Some(first)) = (cursor, journal.entries.first()) {
+            if kms.derive(first.block)? != first.new {
+                return Err(invalid("journal does not match the scheme").into());
+            }
+        }
+
+        Ok(self.rewrite(&journal, cursor, blocks)?)
+    }
+
+    /// Re-encrypts the journaled blocks from `cursor` on, then discards the journal.
+    fn rewrite(
+        &mut self,
+        journal: &Journal,
+        cursor: u64,
+        blocks: &mut impl BlockStore,
+    ) -> Result<u64, KmsError> {
+        let mut rewritten = 0;
+        for (i, entry) in journal.entries.iter().enumerate().skip(cursor as usize) {
+            let ciphertext = blocks.read(entry.block)?;
+
+            // A crash may have struck after the block was written but before the cursor was.
+            if let Ok(mut plaintext) = blocks.decrypt(&entry.old, entry.block, &ciphertext) {
+                let reencrypted = blocks.encrypt(&entry.new, entry.block, &plaintext);
+                plaintext.zeroize();
+                blocks.write(entry.block, &reencrypted?)?;
+                rewritten += 1;
+            } else if blocks
+                .decrypt(&entry.new, entry.block, &ciphertext)
+                .is_err()
+            {
+                return Err(invalid("block is under neither its old nor its new key"));
+            }
+
+            let cursor = self.cursor();
+            self.storage
+                .replace(&cursor, &(i as u64 + 1).to_le_bytes())?;
+        }
+
+        self.discard()?;
+        Ok(rewritten)
+    }
+
+    /// Returns whether the persisted state of the scheme is exactly `state`.
+    fn holds(&mut self, state: &[u8]) -> Result<bool, KmsError> {
+        let mut stored = self.storage.read(&self.name)?;
+        let holds = stored.as_deref() == Some(state);
+        stored.zeroize();
+        Ok(holds)
+    }
+
+    fn cursor(&self) -> String {
+        format!("{}.cursor", self.name)
+    }
+
+    fn journal(&self) -> String {
+        format!("{}.journal", self.name)
+    }
+
+    fn write_journal(&mut self, journal: &Journal) -> Result<(), KmsError> {
+        let mut bytes = Vec::new();
+        write_u64(&mut bytes, journal.state.len() as u64)?;
+        bytes.extend_from_slice(&journal.state);
+        write_u64(&mut bytes, journal.entries.len() as u64)?;
+        for entry in &journal.entries {
+            write_u64(&mut bytes, entry.block)?;
+            bytes.extend_from_slice(entry.old.as_bytes());
+            bytes.extend_from_slice(entry.new.as_bytes());
+        }
+
+        self.storage.discard(&self.cursor())?;
+        let replaced = self.storage.replace(&self.journal(), &bytes);
+        bytes.zeroize();
+        replaced
+    }
+
+    fn read_journal(&mut self) -> Result<Option<Journal>, KmsError> {
+        let Some(mut bytes) = self.storage.read(&self.journal())? else {
+            return Ok(None);
+        };
+
+        let journal = parse_journal(&bytes);
+        bytes.zeroize();
+        journal.map(Some)
+    }
+
+    /// Securely discards the journal and cursor.
+    fn discard(&mut self) -> Result<(), KmsError> {
+        self.storage.discard(&self.journal())?;
+        self.storage.discard(&self.cursor())
+    }
+}
+
+/// Parses a journal written by `Reencryptor::write_journal()`.
+fn parse_journal(bytes: &[u8]) -> Result<Journal, KmsError> {
+    let mut reader = bytes;
+    let len = read_u64(&mut reader)?;
+    if (reader.len() as u64) < len {
+        return Err(invalid("truncated journal"));
+    }
+    let (state, mut reader) = reader.split_at(len as usize);
+
+    let mut entries = Vec::new();
+    for _ in 0..read_u64(&mut reader)? {
+        entries.push(Entry {
+            block: read_u64(&mut reader)?,
+            old: read_key(&mut reader)?,
+            new: read_key(&mut reader)?,
+        });
+    }
+
+    Ok(Journal {
+        state: state.to_vec(),
+        entries,
+    })
+}
@@ -0,0 +1,453 @@
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    KeyInit, XChaCha20Poly1305, XNonce,
+};
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+use crate::{
+    codec::{invalid, read_array},
+    format, Abort, Key, KeyManagementScheme, KmsError, Storage, Versioned, KEY_LEN,
+};
+
+/// The magic number opening every sealed container.
+const MAGIC: [u8; 4] = *b"\x89KME";
+
+/// The current version of the sealed container format.
+const VERSION: u16 = 1;
+
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+/// The largest Argon2id costs accepted from a header, unless a passphrase's own are larger.
+const MAX_M_COST: u32 = 1 << 20;
+const MAX_T_COST: u32 = 64;
+const MAX_P_COST: u32 = 64;
+
+/// A key under which persisted state is encrypted.
+#[derive(Clone)]
+pub struct MasterKey(Key);
+
+impl MasterKey {
+    /// Wraps the given key.
+    pub fn new(key: Key) -> Self {
+        Self(key)
+    }
+
+    /// Generates a fresh random master key.
+    pub fn random(rng: &mut (impl RngCore + CryptoRng)) -> Self {
+        Self(Key::random(rng))
+    }
+
+    /// Reads a master key from a file holding either the raw key bytes or their hex encoding.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, KmsError> {
+        let mut bytes = fs::read(path)?;
+        let key = match <[u8; KEY_LEN]>::try_from(&bytes[..]) {
+            Ok(raw) => Ok(Self(Key::new(raw))),
+            Err(_) => Self::from_hex(&bytes),
+        };
+        bytes.zeroize();
+        key
+    }
+
+    /// Reads a hex-encoded master key from an environment variable.
+    pub fn from_env(var: &str) -> Result<Self, KmsError> {
+        let mut hex = env::var(var)
+            .map_err(|err| io::Error::new(io::ErrorKind::NotFound, format!("{var}: {err}")))?;
+        let key = Self::from_hex(hex.as_bytes());
+        hex.zeroize();
+        key
+    }
+
+    /// Decodes a master key from hex digits, ignoring surrounding whitespace.
+    fn from_hex(hex: &[u8]) -> Result<Self, KmsError> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed master key");
+
+        let hex = hex.trim_ascii();
+        let mut key = Key::default();
+        if hex.len() != 2 * KEY_LEN {
+            return Err(malformed().into());
+        }
+
+        for (byte, digits) in key.as_mut_bytes().iter_mut().zip(hex.chunks(2)) {
+            let digit = |d: u8| (d as char).to_digit(16).ok_or_else(malformed);
+            *byte = (digit(digits[0])? << 4 | digit(digits[1])?) as u8;
+        }
+        Ok(Self(key))
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(self.0.as_bytes().into())
+    }
+}
+
+/// How the master key of sealed state is derived, as recorded in its header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Kdf {
+    /// The master key is used as is.
+    Raw,
+    /// The master key is derived from a passphrase with Argon2id.
+    Argon2id {
+        /// The random salt.
+        salt: [u8; SALT_LEN],
+        /// The memory cost in KiB.
+        m_cost: u32,
+        /// The number of iterations.
+        t_cost: u32,
+        /// The degree of parallelism.
+        p_cost: u32,
+    },
+}
+
+impl Kdf {
+    fn write(&self, header: &mut Vec<u8>) {
+        match self {
+            Self::Raw => header.push(0),
+            Self::Argon2id {
+                salt,
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                header.push(1);
+                header.extend_from_slice(salt);
+                for cost in [m_cost, t_cost, p_cost] {
+                    header.extend_from_slice(&cost.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    fn read(reader: &mut &[u8]) -> Result<Self, KmsError> {
+        match read_array(reader)? {
+            [0] => Ok(Self::Raw),
+            [1] => {
+                let salt = read_array(reader)?;
+                let mut cost = || read_array(reader).map(u32::from_le_bytes);
+                Ok(Self::Argon2id {
+                    salt,
+                    m_cost: cost()?,
+                    t_cost: cost()?,
+                    p_cost: cost()?,
+                })
+            }
+            _ => Err(invalid("unknown key derivation")),
+        }
+    }
+}
+
+/// A source of the master key that state is sealed under.
+pub trait KeyProvider {
+    /// Returns a key to seal state under, along with how to derive it again.
+    fn seal_key(&self) -> Result<(MasterKey, Kdf), KmsError>;
+
+    /// Recovers the key that state was sealed under, given how it was derived.
+    fn open_key(&self, kdf: &Kdf) -> Result<MasterKey, KmsError>;
+}
+
+impl KeyProvider for MasterKey {
+    fn seal_key(&self) -> Result<(MasterKey, Kdf), KmsError> {
+        Ok((self.clone(), Kdf::Raw))
+    }
+
+    fn open_key(&self, kdf: &Kdf) -> Result<MasterKey, KmsError> {
+        match kdf {
+            Kdf::Raw => Ok(self.clone()),
+            _ => Err(invalid("state is sealed under a derived key")),
+        }
+    }
+}
+
+/// A passphrase from which master keys are derived with Argon2id.
+///
+/// Every sealing key is derived under a fresh random salt, which is recorded in the sealed header
+/// along with the cost parameters. The header is read before it can be authenticated, so state
+/// whose costs exceed both the passphrase's own and 1 GiB of memory, 64 iterations, or a
+/// parallelism of 64 is rejected as corrupted rather than derived.
+pub struct Passphrase {
+    passphrase: Vec<u8>,
+    params: Params,
+}
+
+impl Passphrase {
+    /// Uses the given passphrase with the default Argon2id cost parameters.
+    pub fn new(passphrase: impl AsRef<[u8]>) -> Self {
+        Self {
+            passphrase: passphrase.as_ref().to_vec(),
+            params: Params::default(),
+        }
+    }
+
+    /// Uses the given passphrase with the given memory cost in KiB, number of iterations, and
+    /// degree of parallelism.
+    ///
+    /// Fails with [`KmsError::Io`] of kind [`io::ErrorKind::InvalidInput`] if Argon2id rejects the
+    /// costs.
+    pub fn with_costs(
+        passphrase: impl AsRef<[u8]>,
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    ) -> Result<Self, KmsError> {
+        Ok(Self {
+            passphrase: passphrase.as_ref().to_vec(),
+            params: Params::new(m_cost, t_cost, p_cost, Some(KEY_LEN))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?,
+        })
+    }
+
+    fn derive(&self, salt: &[u8], params: Params) -> Result<MasterKey, KmsError> {
+        let mut key = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(&self.passphrase, salt, key.as_mut_bytes())
+            .map_err(KmsError::backend)?;
+        Ok(MasterKey(key))
+    }
+}
+
+impl KeyProvider for Passphrase {
+    fn seal_key(&self) -> Result<(MasterKey, Kdf), KmsError> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.try_fill_bytes(&mut salt)?;
+
+        let kdf = Kdf::Argon2id {
+            salt,
+            m_cost: self.params.m_cost(),
+            t_cost: self.params.t_cost(),
+            p_cost: self.params.p_cost(),
+        };
+        Ok((self.derive(&salt, self.params.clone())?, kdf))
+    }
+
+    fn open_key(&self, kdf: &Kdf) -> Result<MasterKey, KmsError> {
+        match kdf {
+            Kdf::Argon2id {
+                salt,
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                if *m_cost > self.params.m_cost().max(MAX_M_COST)
+                    || *t_cost > self.params.t_cost().max(MAX_T_COST)
+                    || *p_cost > self.params.p_cost().max(MAX_P_COST)
+                {
+                    return Err(invalid("key derivation costs exceed the limits"));
+                }
+                let params = Params::new(*m_cost, *t_cost, *p_cost, Some(KEY_LEN))
+                    .map_err(|_| invalid("invalid key derivation costs"))?;
+                self.derive(salt, params)
+            }
+            _ => Err(invalid("state is not sealed under a passphrase")),
+        }
+    }
+}
+
+impl Drop for Passphrase {
+    fn drop(&mut self) {
+        self.passphrase.zeroize();
+    }
+}
+
+/// A persisted scheme whose state is encrypted and authenticated under a master key.
+///
+/// The state is written in the [`format`](mod@crate::format) container, then sealed with
+/// XChaCha20-Poly1305 under a fresh random nonce. The header records how the master key was
+/// derived, and is authenticated along with the state. Loading fails with
+/// [`KmsError::Corrupted`] if the sealed state was tampered with or sealed under another master
+/// key.
+pub struct Sealed<K> {
+    inner: K,
+    master: MasterKey,
+    kdf: Kdf,
+}
+
+impl<K> Sealed<K>
+where
+    K: Versioned,
+    K::Error: From<KmsError>,
+{
+    /// Seals the state of `inner` under a key from `provider` whenever it is persisted.
+    pub fn new(inner: K, provider: &impl KeyProvider) -> Result<Self, K::Error> {
+        let (master, kdf) = provider.seal_key()?;
+        Ok(Self { inner, master, kdf })
+    }
+
+    /// Seals the state under a new key from `provider` from now on, leaving every data key as is.
+    ///
+    /// State persisted before re-keying remains sealed under the old key.
+    pub fn rekey(&mut self, provider: &impl KeyProvider) -> Result<(), K::Error> {
+        (self.master, self.kdf) = provider.seal_key()?;
+        Ok(())
+    }
+
+    /// Returns the wrapped scheme.
+    pub fn into_inner(self) -> K {
+        self.inner
+    }
+
+    /// Borrows the wrapped scheme.
+    pub fn inner(&self) -> &K {
+        &self.inner
+    }
+
+    /// Writes the sealed state of the wrapped scheme as of the last `commit()`.
+    pub fn persist(&mut self, mut writer: impl Write) -> Result<(), K::Error> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        self.kdf.write(&mut header);
+
+        let mut nonce = XNonce::default();
+        OsRng.try_fill_bytes(&mut nonce).map_err(KmsError::from)?;
+        header.extend_from_slice(&nonce);
+
+        let mut state = Vec::new();
+        format::encode(&mut self.inner, &mut state)?;
+        let sealed = self.master.cipher().encrypt(
+            &nonce,
+            Payload {
+                msg: &state,
+                aad: &header,
+            },
+        );
+        state.zeroize();
+        let sealed = sealed.expect("state fits in a single message");
+
+        writer
+            .write_all(&header)
+            .and_then(|_| writer.write_all(&sealed))
+            .and_then(|_| writer.flush())
+            .map_err(KmsError::from)?;
+        Ok(())
+    }
+
+    /// Opens state previously written by `persist()`, authenticating it under a key recovered
+    /// from `provider`.
+    ///
+    /// The state keeps being sealed under the recovered key until it is re-keyed.
+    pub fn load(provider: &impl KeyProvider, mut reader: impl Read) -> Result<Self, K::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(KmsError::from)?;
+
+        let (kdf, header_len) = read_header(&bytes)?;
+        let master = provider.open_key(&kdf)?;
+
+        let (header, sealed) = bytes.split_at(header_len);
+        let mut state = master
+            .cipher()
+            .decrypt(
+                XNonce::from_slice(&header[header_len - NONCE_LEN..]),
+                Payload {
+                    msg: sealed,
+                    aad: header,
+                },
+            )
+            .map_err(|_| invalid("sealed state failed authentication"))?;
+
+        let inner = format::decode::<K>(&state[..]);
+        state.zeroize();
+        Ok(Self {
+            inner: inner?,
+            master,
+            kdf,
+        })
+    }
+
+    /// Persists the sealed state to the named blob in `storage`, as by `Versioned::persist_to()`.
+    pub fn persist_to(&mut self, storage: &mut impl Storage, name: &str) -> Result<(), K::Error> {
+        let mut bytes = Vec::new();
+        self.persist(&mut bytes)?;
+        Ok(storage.replace(name, &bytes)?)
+    }
+
+    /// Opens the sealed state in the named blob in `storage`, previously written by
+    /// `persist_to()`.
+    pub fn load_from(
+        provider: &impl KeyProvider,
+        storage: &mut impl Storage,
+        name: &str,
+    ) -> Result<Self, K::Error> {
+        let bytes = storage
+            .read(name)?
+            .ok_or_else(|| KmsError::Io(io::ErrorKind::NotFound.into()))?;
+        Self::load(provider, &bytes[..])
+    }
+}
+
+/// Parses the header of sealed state, returning how its key was derived and its length.
+fn read_header(bytes: &[u8]) -> Result<(Kdf, usize), KmsError> {
+    let mut reader = bytes
+        .strip_prefix(&MAGIC)
+        .ok_or_else(|| invalid("not a sealed state"))?;
+
+    if u16::from_le_bytes(read_array(&mut reader)?) != VERSION {
+        return Err(invalid("unsupported sealed format version"));
+    }
+    let kdf = Kdf::read(&mut reader)?;
+
+    let header_len = bytes.len() - reader.len() + NONCE_LEN;
+    if header_len > bytes.len() {
+        return Err(invalid("truncated state"));
+    }
+    Ok((kdf, header_len))
+}
+
+impl<K: KeyManagementScheme> KeyManagementScheme for Sealed<K> {
+    type Key = K::Key;
+    type KeyId = K::KeyId;
+    type Error = K::Error;
+
+    fn derive(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.inner.derive(key)
+    }
+
+    fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.inner.update(key)
+    }
+
+    fn derive_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        self.inner.derive_many(keys)
+    }
+
+    fn update_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        self.inner.update_many(keys)
+    }
+
+    fn revoke(&mut self, key: Self::KeyId) -> Result<(), Self::Error> {
+        self.inner.revoke(key)
+    }
+
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Option<Self::Key>)>, Self::Error> {
+        self.inner.commit(rng)
+    }
+
+    fn commit_with(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+        visit: impl FnMut(Self::KeyId, Option<Self::Key>),
+    ) -> Result<(), Self::Error> {
+        self.inner.commit_with(rng, visit)
+    }
+}
+
+impl<K: Abort> Abort for Sealed<K> {
+    fn abort(&mut self) {
+        self.inner.abort();
+    }
+}
@@ -0,0 +1,127 @@
+use core::fmt::{self, Debug};
+
+use rand::{CryptoRng, RngCore};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+/// A secret key of `N` bytes.
+///
+/// The key is zeroized when dropped, redacted from `Debug` output, and compared in constant time.
+#[derive(Clone)]
+pub struct SecretKey<const N: usize>([u8; N]);
+
+impl<const N: usize> SecretKey<N> {
+    /// Wraps the given key bytes.
+    pub fn new(bytes: [u8; N]) -> Self {
+        Self(bytes)
+    }
+
+    /// Generates a fresh random key.
+    pub fn random(rng: &mut (impl RngCore + CryptoRng)) -> Self {
+        let mut key = Self::default();
+        rng.fill_bytes(&mut key.0);
+        key
+    }
+
+    /// Exposes the key bytes.
+    pub fn as_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+
+    /// Exposes the key bytes for in-place initialization.
+    pub(crate) fn as_mut_bytes(&mut self) -> &mut [u8; N] {
+        &mut self.0
+    }
+}
+
+impl<const N: usize> Default for SecretKey<N> {
+    fn default() -> Self {
+        Self([0; N])
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for SecretKey<N> {
+    fn from(bytes: [u8; N]) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl<const N: usize> Debug for SecretKey<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey<{N}>(REDACTED)")
+    }
+}
+
+impl<const N: usize> ConstantTimeEq for SecretKey<N> {
+    fn ct_eq(&self, other: &Self) -> subtle::Choice {
+        self.0.ct_eq(&other.0)
+    }
+}
+
+impl<const N: usize> PartialEq for SecretKey<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ct_eq(other).into()
+    }
+}
+
+impl<const N: usize> Eq for SecretKey<N> {}
+
+impl<const N: usize> Zeroize for SecretKey<N> {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const N: usize> serde::Serialize for SecretKey<N> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, const N: usize> serde::Deserialize<'de> for SecretKey<N> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::{Error, SeqAccess, Visitor};
+
+        struct Bytes<const N: usize>;
+
+        impl<'de, const N: usize> Visitor<'de> for Bytes<N> {
+            type Value = SecretKey<N>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{N} key bytes")
+            }
+
+            fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+                if bytes.len() != N {
+                    return Err(E::invalid_length(bytes.len(), &self));
+                }
+                let mut key = SecretKey::default();
+                key.0.copy_from_slice(bytes);
+                Ok(key)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut key = SecretKey::default();
+                for (i, byte) in key.0.iter_mut().enumerate() {
+                    *byte = seq
+                        .next_element()?
+                        .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+                }
+                if seq.next_element::<u8>()?.is_some() {
+                    return Err(A::Error::invalid_length(N + 1, &self));
+                }
+                Ok(key)
+            }
+        }
+
+        deserializer.deserialize_bytes(Bytes)
+    }
+}
+
+impl<const N: usize> Drop for SecretKey<N> {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use rand::{CryptoRng, RngCore};
+
+use crate::{Abort, KeyManagementScheme};
+
+/// A trait for key management schemes that can derive keys without being mutated.
+pub trait SharedDerive: KeyManagementScheme {
+    /// Derive the key corresponding to the given `KeyId` through a shared reference, as by
+    /// `derive()`.
+    ///
+    /// Returns `None` if deriving the key requires mutating `self`, such as to create it.
+    fn try_derive(&self, key: Self::KeyId) -> Option<Result<Self::Key, Self::Error>>;
+}
+
+/// A handle to a scheme that can be shared between threads.
+///
+/// Keys are derived in parallel under a shared lock, falling back to an exclusive lock only for
+/// keys that the scheme must create. Updates, revocations, and commits take the exclusive lock,
+/// so a derivation that starts after a commit returns never observes the state before it.
+pub struct SharedKms<K> {
+    inner: RwLock<K>,
+}
+
+impl<K> SharedKms<K>
+where
+    K: SharedDerive,
+    K::KeyId: Clone,
+{
+    /// Shares `inner` between threads.
+    pub fn new(inner: K) -> Self {
+        Self {
+            inner: RwLock::new(inner),
+        }
+    }
+
+    /// Returns the shared scheme.
+    pub fn into_inner(self) -> K {
+        self.inner.into_inner().expect("scheme lock poisoned")
+    }
+
+    /// Derive the key corresponding to the given `KeyId`.
+    pub fn derive(&self, key: K::KeyId) -> Result<K::Key, K::Error> {
+        if let Some(derived) = self.read().try_derive(key.clone()) {
+            return derived;
+        }
+        self.write().derive(key)
+    }
+
+    /// Derive the keys corresponding to each of the given `KeyId`s, in order.
+    ///
+    /// The keys are derived under a single shared lock, unless the scheme must create any of
+    /// them, in which case they are all derived under a single exclusive lock.
+    pub fn derive_many(
+        &self,
+        keys: impl IntoIterator<Item = K::KeyId>,
+    ) -> Result<Vec<K::Key>, K::Error> {
+        let keys: Vec<_> = keys.into_iter().collect();
+        let shared: Option<Vec<_>> = {
+            let inner = self.read();
+            keys.iter()
+                .map(|key| inner.try_derive(key.clone()))
+                .collect()
+        };
+
+        match shared {
+            Some(derived) => derived.into_iter().collect(),
+            None => self.write().derive_many(keys),
+        }
+    }
+
+    /// Update the key corresponding to the given `KeyId`.
+    pub fn update(&self, key: K::KeyId) -> Result<K::Key, K::Error> {
+        self.write().update(key)
+    }
+
+    /// Update the keys corresponding to each of the given `KeyId`s, in order, under a single
+    /// exclusive lock.
+    pub fn update_many(
+        &self,
+        keys: impl IntoIterator<Item = K::KeyId>,
+    ) -> Result<Vec<K::Key>, K::Error> {
+        self.write().update_many(keys)
+    }
+
+    /// Revoke the key corresponding to the given `KeyId`.
+    pub fn revoke(&self, key: K::KeyId) -> Result<(), K::Error> {
+        self.write().revoke(key)
+    }
+
+    /// Commits any deferred key updates and revocations.
+    #[allow(clippy::type_complexity)]
+    pub fn commit(
+        &self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(K::KeyId, Option<K::Key>)>, K::Error> {
+        self.write().commit(rng)
+    }
+
+    /// Commits any deferred key updates and revocations, passing each changed key to `visit`.
+    ///
+    /// Every derivation waits until `visit` has seen every changed key.
+    pub fn commit_with(
+        &self,
+        rng: impl RngCore + CryptoRng,
+        visit: impl FnMut(K::KeyId, Option<K::Key>),
+    ) -> Result<(), K::Error> {
+        self.write().commit_with(rng, visit)
+    }
+
+    /// Discards every uncommitted update and revocation.
+    pub fn abort(&self)
+    where
+        K: Abort,
+    {
+        self.write().abort()
+    }
+
+    /// Takes shared access to the scheme.
+    ///
+    /// Panics if a thread panicked while holding exclusive access, as the scheme may have been
+    /// left mid-update.
+    pub fn read(&self) -> RwLockReadGuard<'_, K> {
+        self.inner.read().expect("scheme lock poisoned")
+    }
+
+    /// Takes exclusive access to the scheme, such as to stage several updates and commit them
+    /// without any derivation observing the updates in between.
+    pub fn write(&self) -> RwLockWriteGuard<'_, K> {
+        self.inner.write().expect("scheme lock poisoned")
+    }
+}
@@ -0,0 +1,80 @@
+use std::ops::{Deref, DerefMut};
+
+use rand::{CryptoRng, RngCore};
+
+use crate::KeyManagementScheme;
+
+/// A trait for key management schemes that can discard their uncommitted updates.
+pub trait Abort: KeyManagementScheme {
+    /// Discards every update and revocation since the last `commit()`, so that each `KeyId`
+    /// derives its committed key again.
+    fn abort(&mut self);
+}
+
+/// A guard staging updates to a scheme, which are discarded unless committed through the guard.
+///
+/// Schemes keep no savepoints, so aborting also discards any updates staged before the guard was
+/// created.
+pub struct Staged<'a, K: Abort> {
+    kms: &'a mut K,
+    done: bool,
+}
+
+impl<'a, K: Abort> Staged<'a, K> {
+    /// Starts staging updates to `kms`.
+    pub fn new(kms: &'a mut K) -> Self {
+        Self { kms, done: false }
+    }
+
+    /// Commits the staged updates.
+    ///
+    /// If the commit fails, the staged updates are discarded.
+    #[allow(clippy::type_complexity)]
+    pub fn commit(
+        mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(K::KeyId, Option<K::Key>)>, K::Error> {
+        let changes = self.kms.commit(rng)?;
+        self.done = true;
+        Ok(changes)
+    }
+
+    /// Commits the staged updates, passing each changed key to `visit` as by
+    /// `KeyManagementScheme::commit_with()`.
+    ///
+    /// If the commit fails, the staged updates are discarded.
+    pub fn commit_with(
+        mut self,
+        rng: impl RngCore + CryptoRng,
+        visit: impl FnMut(K::KeyId, Option<K::Key>),
+    ) -> Result<(), K::Error> {
+        self.kms.commit_with(rng, visit)?;
+        self.done = true;
+        Ok(())
+    }
+
+    /// Discards the staged updates, as does dropping the guard.
+    pub fn abort(self) {}
+}
+
+impl<K: Abort> Deref for Staged<'_, K> {
+    type Target = K;
+
+    fn deref(&self) -> &K {
+        self.kms
+    }
+}
+
+impl<K: Abort> DerefMut for Staged<'_, K> {
+    fn deref_mut(&mut self) -> &mut K {
+        self.kms
+    }
+}
+
+impl<K: Abort> Drop for Staged<'_, K> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.kms.abort();
+        }
+    }
+}
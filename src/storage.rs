@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use zeroize::Zeroize;
+
+use crate::KmsError;
+
+/// A backend holding named blobs of persisted state.
+pub trait Storage {
+    /// Atomically replaces the contents of the named blob, creating it if needed.
+    ///
+    /// The replaced contents should be discarded as by `discard()`.
+    fn replace(&mut self, name: &str, bytes: &[u8]) -> Result<(), KmsError>;
+
+    /// Reads the contents of the named blob, or `None` if it does not exist.
+    fn read(&mut self, name: &str) -> Result<Option<Vec<u8>>, KmsError>;
+
+    /// Securely discards the named blob, if it exists.
+    fn discard(&mut self, name: &str) -> Result<(), KmsError>;
+}
+
+/// A storage backend that keeps blobs in memory, zeroizing them once replaced or discarded.
+#[derive(Default)]
+pub struct MemStorage {
+    blobs: HashMap<String, Vec<u8>>,
+}
+
+impl MemStorage {
+    /// Creates an empty backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemStorage {
+    fn replace(&mut self, name: &str, bytes: &[u8]) -> Result<(), KmsError> {
+        if let Some(mut old) = self.blobs.insert(name.into(), bytes.to_vec()) {
+            old.zeroize();
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, name: &str) -> Result<Option<Vec<u8>>, KmsError> {
+        Ok(self.blobs.get(name).cloned())
+    }
+
+    fn discard(&mut self, name: &str) -> Result<(), KmsError> {
+        if let Some(mut old) = self.blobs.remove(name) {
+            old.zeroize();
+        }
+        Ok(())
+    }
+}
+
+/// A storage backend that keeps each blob in a file within a directory.
+///
+/// Blobs are replaced by writing a temporary file and renaming it over the old one, syncing both
+/// the file and the directory. Replaced and discarded files are overwritten with zeros before they
+/// are released, which is only as secure as the underlying filesystem and device allow.
+///
+/// Names must be plain file names: a name that is empty, contains a path separator, or contains
+/// `..` is rejected with an [`io::ErrorKind::InvalidInput`] error.
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    /// Opens a backend over the given directory, creating it if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, KmsError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Returns the path of the named blob, rejecting names that could escape the directory.
+    fn path(&self, name: &str) -> io::Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+            let reason = format!("invalid blob name {name:?}");
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, reason));
+        }
+        Ok(self.dir.join(name))
+    }
+
+    /// Durably records changes to the directory's entries.
+    fn sync_dir(&self) -> io::Result<()> {
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+impl Storage for FileStorage {
+    fn replace(&mut self, name: &str, bytes: &[u8]) -> Result<(), KmsError> {
+        let path = self.path(name)?;
+        let old = open_existing(&path)?;
+
+        let tmp = self.dir.join(format!("{name}.tmp"));
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+
+        fs::rename(&tmp, &path)?;
+        self.sync_dir()?;
+
+        // The old file is unlinked but still open, so its contents can be scrubbed.
+        if let Some(old) = old {
+            overwrite(old)?;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, name: &str) -> Result<Option<Vec<u8>>, KmsError> {
+        match fs::read(self.path(name)?) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn discard(&mut self, name: &str) -> Result<(), KmsError> {
+        let path = self.path(name)?;
+        if let Some(file) = open_existing(&path)? {
+            overwrite(file)?;
+            fs::remove_file(&path)?;
+            self.sync_dir()?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens the file at `path` for writing, or `None` if it does not exist.
+fn open_existing(path: &PathBuf) -> io::Result<Option<File>> {
+    match File::options().write(true).open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Durably overwrites the contents of `file` with zeros.
+fn overwrite(mut file: File) -> io::Result<()> {
+    let len = file.metadata()?.len();
+    let zeros = [0; 4096];
+
+    let mut written = 0;
+    while written < len {
+        let n = zeros.len().min((len - written) as usize);
+        file.write_all(&zeros[..n])?;
+        written += n as u64;
+    }
+    file.sync_data()
+}
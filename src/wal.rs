@@ -0,0 +1,219 @@
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+};
+
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    codec::{invalid, read_array, read_u64},
+    Abort, KeyManagementScheme, KmsError, Persist,
+};
+
+/// A file-like medium holding the log or the state of a [`Wal`].
+pub trait Medium: Read + Write + Seek {
+    /// Durably writes out everything written so far.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Truncates or extends the medium to `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Medium for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+impl Medium for Cursor<Vec<u8>> {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+/// A write-ahead log making the commits of a persisted scheme crash-consistent.
+///
+/// The state of the wrapped scheme is persisted in place on every `commit()`. Before the state is
+/// overwritten, the committed state is recorded in the log, and the log is cleared once the state
+/// has been written. Both the cleared log record and any tail left over from a longer state are
+/// overwritten with zeros, so keys dropped by a commit do not linger on either medium.
+///
+/// Opening a `Wal` replays a complete log record, or rolls back a torn one, so a crash at any
+/// point leaves either the state before or the state after the commit.
+pub struct Wal<K, M> {
+    inner: K,
+    log: M,
+    state: M,
+}
+
+impl<K, M> Wal<K, M>
+where
+    K: Persist,
+    K::Error: From<KmsError>,
+    M: Medium,
+{
+    /// Starts logging commits of `inner`, persisting its current state to `state`.
+    pub fn create(mut inner: K, mut log: M, mut state: M) -> Result<Self, K::Error> {
+        let mut bytes = Vec::new();
+        inner.persist(&mut bytes)?;
+
+        write_record(&mut state, &bytes)?;
+        clear_log(&mut log)?;
+
+        Ok(Self { inner, log, state })
+    }
+
+    /// Opens a logged scheme, recovering from a crash during its last commit.
+    pub fn open(mut log: M, mut state: M) -> Result<Self, K::Error> {
+        let inner = match read_record(&mut log)? {
+            // The commit was logged in full, so apply it again.
+            Some(bytes) => {
+                write_record(&mut state, &bytes)?;
+                clear_log(&mut log)?;
+                K::load(&bytes[..])?
+            }
+            // The commit never made it into the log, so the state was never touched.
+            None => {
+                clear_log(&mut log)?;
+                let bytes = read_record(&mut state)?.ok_or_else(|| invalid("torn state"))?;
+                K::load(&bytes[..])?
+            }
+        };
+
+        Ok(Self { inner, log, state })
+    }
+}
+
+impl<K, M> KeyManagementScheme for Wal<K, M>
+where
+    K: Persist,
+    K::Error: From<KmsError>,
+    M: Medium,
+{
+    type Key = K::Key;
+    type KeyId = K::KeyId;
+    type Error = K::Error;
+
+    fn derive(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.inner.derive(key)
+    }
+
+    fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.inner.update(key)
+    }
+
+    fn derive_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        self.inner.derive_many(keys)
+    }
+
+    fn update_many(
+        &mut self,
+        keys: impl IntoIterator<Item = Self::KeyId>,
+    ) -> Result<Vec<Self::Key>, Self::Error> {
+        self.inner.update_many(keys)
+    }
+
+    fn revoke(&mut self, key: Self::KeyId) -> Result<(), Self::Error> {
+        self.inner.revoke(key)
+    }
+
+    /// Commits the wrapped scheme and durably persists its new state.
+    ///
+    /// If this fails, the persisted state may be that from before or after the commit, and the
+    /// scheme must be reopened with [`Wal::open()`].
+    ///
+    /// Changed keys are only reported once the new state is durable, so `commit_with()` collects
+    /// them all before visiting any.
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Option<Self::Key>)>, Self::Error> {
+        let changes = self.inner.commit(rng)?;
+
+        let mut bytes = Vec::new();
+        self.inner.persist(&mut bytes)?;
+
+        write_record(&mut self.log, &bytes)?;
+        write_record(&mut self.state, &bytes)?;
+        clear_log(&mut self.log)?;
+
+        Ok(changes)
+    }
+}
+
+impl<K, M> Abort for Wal<K, M>
+where
+    K: Persist + Abort,
+    K::Error: From<KmsError>,
+    M: Medium,
+{
+    fn abort(&mut self) {
+        self.inner.abort();
+    }
+}
+
+/// Durably writes `bytes` at the start of `medium`, framed by its length and checksum, zeroing
+/// and truncating whatever followed the previous record.
+fn write_record(medium: &mut impl Medium, bytes: &[u8]) -> Result<(), KmsError> {
+    medium.seek(SeekFrom::Start(0))?;
+    medium.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    medium.write_all(bytes)?;
+    medium.write_all(&Sha256::digest(bytes))?;
+
+    let end = medium.stream_position()?;
+    zero_from(medium, end)?;
+    Ok(medium.sync()?)
+}
+
+/// Reads the record at the start of `medium`, or `None` if it is empty, torn, or corrupted.
+fn read_record(medium: &mut impl Medium) -> Result<Option<Vec<u8>>, KmsError> {
+    medium.seek(SeekFrom::Start(0))?;
+
+    let mut bytes = Vec::new();
+    let checksum = read_u64(medium).and_then(|len| {
+        medium.take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(invalid("truncated record"));
+        }
+        read_array::<32>(medium)
+    });
+
+    match checksum {
+        Ok(checksum) if bytes.is_empty() || checksum != *Sha256::digest(&bytes) => Ok(None),
+        Ok(_) => Ok(Some(bytes)),
+        Err(KmsError::Corrupted(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Durably marks the log as holding no record, overwriting the previous one with zeros.
+fn clear_log(log: &mut impl Medium) -> Result<(), KmsError> {
+    zero_from(log, 0)?;
+    Ok(log.sync()?)
+}
+
+/// Overwrites everything in `medium` from `start` onwards with zeros, then truncates it there.
+fn zero_from(medium: &mut impl Medium, start: u64) -> io::Result<()> {
+    let len = medium.seek(SeekFrom::End(0))?;
+    if len > start {
+        medium.seek(SeekFrom::Start(start))?;
+        medium.write_all(&vec![0; (len - start) as usize])?;
+        medium.flush()?;
+        medium.sync()?;
+    }
+    medium.set_len(start)?;
+    medium.flush()
+}
@@ -0,0 +1,123 @@
+use std::{
+    future::Future,
+    pin::{pin, Pin},
+    task::{Context, Poll, Waker},
+};
+
+use kms::{AsyncKeyManagementScheme, Blocking, KeyManagementScheme, Khf, KmsError};
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+
+const IDS: [u64; 6] = [0, 1, 2, 15, 16, 255];
+
+/// Polls a future to completion, for futures that never wait on anything but themselves.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// Requires that a future can be spawned onto a multi-threaded runtime.
+fn spawnable<F: Future + Send + 'static>(future: F) -> F {
+    future
+}
+
+/// A future that is pending once before resolving, like one awaiting I/O.
+struct YieldOnce<T>(Option<T>, bool);
+
+impl<T: Unpin> Future for YieldOnce<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        if !self.1 {
+            self.1 = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        Poll::Ready(self.0.take().expect("polled after completion"))
+    }
+}
+
+/// An asynchronous scheme implementing only the required methods.
+struct Yielding(Khf);
+
+impl AsyncKeyManagementScheme for Yielding {
+    type Key = kms::Key;
+    type KeyId = u64;
+    type Error = KmsError;
+
+    fn derive(&mut self, key: u64) -> impl Future<Output = Result<kms::Key, KmsError>> + Send {
+        YieldOnce(Some(self.0.derive(key)), false)
+    }
+
+    fn update(&mut self, key: u64) -> impl Future<Output = Result<kms::Key, KmsError>> + Send {
+        YieldOnce(Some(self.0.update(key)), false)
+    }
+
+    fn revoke(&mut self, key: u64) -> impl Future<Output = Result<(), KmsError>> + Send {
+        YieldOnce(Some(self.0.revoke(key)), false)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng + Send,
+    ) -> impl Future<Output = Result<Vec<(u64, Option<kms::Key>)>, KmsError>> + Send {
+        YieldOnce(Some(self.0.commit(rng)), false)
+    }
+}
+
+/// Checks the provided methods against the required ones.
+async fn exercise(kms: &mut impl AsyncKeyManagementScheme<KeyId = u64, Key = kms::Key>) {
+    let mut derived = Vec::new();
+    for id in IDS {
+        derived.push(kms.derive(id).await.unwrap());
+    }
+    assert_eq!(kms.derive_many(IDS).await.unwrap(), derived);
+
+    let updated = kms.update_many([1, 16]).await.unwrap();
+    assert_eq!(kms.derive(1).await.unwrap(), updated[0]);
+    kms.revoke(2).await.unwrap();
+
+    let mut changes = Vec::new();
+    kms.commit_with(OsRng, |id, key| changes.push((id, key)))
+        .await
+        .unwrap();
+    assert!(changes.iter().any(|(id, key)| *id == 2 && key.is_none()));
+    for (id, key) in changes.into_iter().filter(|(id, _)| *id != 2) {
+        assert_eq!(kms.derive(id).await.unwrap(), key.unwrap());
+    }
+}
+
+#[test]
+fn blocking_matches_sync_scheme() {
+    block_on(exercise(&mut Blocking::new(Khf::new(OsRng, &[4, 4]))));
+}
+
+#[test]
+fn provided_methods_await_required_ones() {
+    block_on(exercise(&mut Yielding(Khf::new(OsRng, &[4, 4]))));
+}
+
+#[test]
+fn futures_are_send() {
+    let future = spawnable(async {
+        let mut kms = Blocking::new(Khf::new(OsRng, &[4, 4]));
+        exercise(&mut kms).await;
+        kms.into_inner()
+    });
+    block_on(future).derive(0).unwrap();
+}
+
+#[test]
+fn glob_import_keeps_sync_methods_unambiguous() {
+    use kms::*;
+
+    let mut kms = Khf::new(OsRng, &[4, 4]);
+    let key = kms.derive(1).unwrap();
+    assert_eq!(kms.update(1).unwrap(), kms.derive(1).unwrap());
+    assert_ne!(kms.derive(1).unwrap(), key);
+}
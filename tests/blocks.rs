@@ -0,0 +1,181 @@
+mod common;
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+};
+
+use kms::{Device, EncryptedBlockStore, FileStorage, Khf, KmsError, Kwt, MemStorage, Versioned};
+use rand::rngs::OsRng;
+
+use common::Dir;
+
+const BLOCK_SIZE: usize = 64;
+
+fn device(dir: &Dir) -> File {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.path("device"))
+        .unwrap()
+}
+
+fn storage(dir: &Dir) -> FileStorage {
+    FileStorage::new(dir.path("state")).unwrap()
+}
+
+fn data(block: u64, round: u8) -> Vec<u8> {
+    let mut data = vec![round; BLOCK_SIZE];
+    data[..8].copy_from_slice(&block.to_le_bytes());
+    data
+}
+
+#[test]
+fn memory_round_trip() {
+    let mut store = EncryptedBlockStore::create(
+        Khf::new(OsRng, &[4, 4]),
+        MemStorage::new(),
+        "state",
+        Vec::new(),
+        BLOCK_SIZE,
+    )
+    .unwrap();
+
+    for block in 0..20 {
+        store.write(block, &data(block, 0)).unwrap();
+    }
+    assert_eq!(store.read(3).unwrap(), Some(data(3, 0)));
+    store.sync(OsRng).unwrap();
+
+    store.write(3, &data(3, 1)).unwrap();
+    store.delete(4).unwrap();
+    store.sync(OsRng).unwrap();
+
+    for block in 0..20 {
+        let expected = match block {
+            3 => Some(data(3, 1)),
+            4 => None,
+            _ => Some(data(block, 0)),
+        };
+        assert_eq!(store.read(block).unwrap(), expected);
+    }
+    assert_eq!(store.read(20).unwrap(), None);
+    assert!(store.write(20, &[0; 3]).is_err());
+}
+
+#[test]
+fn rejects_writes_of_wrong_size() {
+    let mut store = EncryptedBlockStore::create(
+        Khf::new(OsRng, &[4, 4]),
+        MemStorage::new(),
+        "state",
+        Vec::new(),
+        BLOCK_SIZE,
+    )
+    .unwrap();
+
+    for len in [0, BLOCK_SIZE - 1, BLOCK_SIZE + 1] {
+        assert!(matches!(
+            store.write(0, &vec![0; len]),
+            Err(KmsError::Io(err)) if err.kind() == io::ErrorKind::InvalidInput
+        ));
+    }
+    assert_eq!(store.read(0).unwrap(), None);
+}
+
+#[test]
+fn rejects_unaddressable_blocks() {
+    let mut store = EncryptedBlockStore::create(
+        Khf::new(OsRng, &[4, 4]),
+        MemStorage::new(),
+        "state",
+        Vec::new(),
+        BLOCK_SIZE,
+    )
+    .unwrap();
+
+    for block in [u64::MAX / 8, u64::MAX] {
+        assert!(matches!(
+            store.write(block, &[0; BLOCK_SIZE]),
+            Err(KmsError::Io(err)) if err.kind() == io::ErrorKind::InvalidInput
+        ));
+    }
+    store.sync(OsRng).unwrap();
+}
+
+#[test]
+fn memory_device_rejects_writes_past_end() {
+    let mut device = Vec::new();
+    assert!(matches!(
+        device.write_at(u64::MAX, &[0; 8]),
+        Err(KmsError::Io(err)) if err.kind() == io::ErrorKind::InvalidInput
+    ));
+    assert!(device.is_empty());
+}
+
+#[test]
+fn memory_device_rejects_reads_past_end() {
+    let mut device = vec![7; 16];
+    let mut buf = [0; 8];
+    device.read_at(8, &mut buf).unwrap();
+    assert_eq!(buf, [7; 8]);
+
+    for offset in [9, 16, u64::MAX] {
+        assert!(matches!(
+            device.read_at(offset, &mut buf),
+            Err(KmsError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+}
+
+fn deleted_blocks_stay_deleted<K>(dir: Dir, kms: K)
+where
+    K: Versioned<KeyId = u64, Key = kms::Key, Error = KmsError>,
+{
+    let mut store =
+        EncryptedBlockStore::create(kms, storage(&dir), "kms", device(&dir), BLOCK_SIZE).unwrap();
+    for block in 0..20 {
+        store.write(block, &data(block, 0)).unwrap();
+    }
+    store.sync(OsRng).unwrap();
+    drop(store);
+    let image = fs::read(dir.path("device")).unwrap();
+
+    let mut store =
+        EncryptedBlockStore::<K, _, _>::open(storage(&dir), "kms", device(&dir), BLOCK_SIZE)
+            .unwrap();
+    store.delete(5).unwrap();
+    store.write(6, &data(6, 1)).unwrap();
+    store.sync(OsRng).unwrap();
+    drop(store);
+
+    let mut store =
+        EncryptedBlockStore::<K, _, _>::open(storage(&dir), "kms", device(&dir), BLOCK_SIZE)
+            .unwrap();
+    for block in (0..20).filter(|block| *block != 5) {
+        let round = u8::from(block == 6);
+        assert_eq!(store.read(block).unwrap(), Some(data(block, round)));
+    }
+    assert_eq!(store.read(5).unwrap(), None);
+    drop(store);
+
+    // Restoring the old ciphertext does not bring back what the scheme has forgotten.
+    fs::write(dir.path("device"), image).unwrap();
+    let mut store =
+        EncryptedBlockStore::<K, _, _>::open(storage(&dir), "kms", device(&dir), BLOCK_SIZE)
+            .unwrap();
+    assert!(matches!(store.read(5), Err(KmsError::Corrupted(_))));
+    assert!(matches!(store.read(6), Err(KmsError::Corrupted(_))));
+}
+
+#[test]
+fn khf_deleted_blocks_stay_deleted() {
+    deleted_blocks_stay_deleted(Dir::new("khf"), Khf::new(OsRng, &[4, 4]));
+}
+
+#[test]
+fn kwt_deleted_blocks_stay_deleted() {
+    deleted_blocks_stay_deleted(Dir::new("kwt"), Kwt::new(OsRng, 4));
+}
@@ -0,0 +1,105 @@
+use kms::{CacheStats, Cached, KeyManagementScheme, Khf, UpdateRange};
+use rand::rngs::OsRng;
+
+fn cached(capacity: usize) -> Cached<Khf> {
+    Cached::new(Khf::new(OsRng, &[4, 4]), capacity)
+}
+
+#[test]
+fn counts_hits_and_misses() {
+    let mut kms = cached(8);
+    let key = kms.derive(1).unwrap();
+    assert_eq!(kms.derive(1).unwrap(), key);
+    assert_eq!(kms.derive(1).unwrap(), key);
+    kms.derive(2).unwrap();
+
+    let stats = kms.stats();
+    assert_eq!(
+        stats,
+        CacheStats {
+            hits: 2,
+            misses: 2,
+            evictions: 0
+        }
+    );
+}
+
+#[test]
+fn evicts_least_recently_used() {
+    let mut kms = cached(2);
+    kms.derive(1).unwrap();
+    kms.derive(2).unwrap();
+    kms.derive(1).unwrap();
+    kms.derive(3).unwrap();
+    assert_eq!(kms.len(), 2);
+    assert_eq!(kms.stats().evictions, 1);
+
+    // 2 was evicted, while 1 and 3 are still cached.
+    kms.derive(1).unwrap();
+    kms.derive(3).unwrap();
+    assert_eq!(kms.stats().hits, 3);
+    kms.derive(2).unwrap();
+    assert_eq!(kms.stats().misses, 4);
+}
+
+#[test]
+fn invalidates_on_update_and_commit() {
+    let mut kms = cached(8);
+    let old = kms.derive(1).unwrap();
+    kms.derive(2).unwrap();
+
+    let new = kms.update(1).unwrap();
+    assert_ne!(new, old);
+    assert_eq!(kms.derive(1).unwrap(), new);
+
+    kms.revoke(2).unwrap();
+    assert!(kms.derive(2).is_err());
+
+    let revoked = kms.derive(3).unwrap();
+    kms.revoke(3).unwrap();
+    for (id, key) in kms.commit(OsRng).unwrap() {
+        if let Some(key) = key {
+            assert_eq!(kms.derive(id).unwrap(), key);
+        }
+    }
+    assert_ne!(kms.derive(3).unwrap(), revoked);
+
+    let ranged = kms.derive(5).unwrap();
+    kms.update_range(4..8).unwrap();
+    assert_ne!(kms.derive(5).unwrap(), ranged);
+}
+
+#[test]
+fn caches_nothing_without_capacity() {
+    let mut kms = cached(0);
+    kms.derive(1).unwrap();
+    kms.derive(1).unwrap();
+    assert!(kms.is_empty());
+    assert_eq!(kms.stats().hits, 0);
+}
+
+#[test]
+fn derives_misses_in_one_batch() {
+    let mut kms = cached(8);
+    let first = kms.derive(1).unwrap();
+
+    let keys = kms.derive_many([1, 2, 3, 2]).unwrap();
+    assert_eq!(keys[0], first);
+    assert_eq!(keys[1], keys[3]);
+    assert_eq!(
+        kms.stats(),
+        CacheStats {
+            hits: 1,
+            misses: 4,
+            evictions: 0
+        }
+    );
+    assert_eq!(
+        kms.derive_many([3, 2]).unwrap(),
+        [keys[2].clone(), keys[1].clone()]
+    );
+    assert_eq!(kms.stats().hits, 3);
+
+    let updated = kms.update_many([1, 2]).unwrap();
+    assert_eq!(kms.derive_many([1, 2]).unwrap(), updated);
+}
@@ -0,0 +1,30 @@
+//! Fixtures shared by the integration tests.
+
+use std::{env, fs, path::PathBuf};
+
+/// A scratch directory, removed once dropped.
+pub struct Dir(PathBuf);
+
+impl Dir {
+    /// Creates a directory unique to `name`, the calling test binary and this process.
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!(
+            "kms-{}-{name}-{}",
+            env!("CARGO_CRATE_NAME"),
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    /// Returns the path of the named entry within the directory.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::Cursor;
+
+use kms::{conformance, Cached, Epoch, Khf, KmsError, Kwt, MasterKey, MemoryKms, Sealed, Wal};
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+
+const IDS: [u64; 8] = [0, 1, 2, 15, 16, 17, 255, u64::MAX];
+
+kms::conformance_tests!(memory, || MemoryKms::new(OsRng), IDS);
+kms::conformance_tests!(khf, || Khf::new(OsRng, &[4, 4]), IDS);
+kms::conformance_tests!(kwt, || Kwt::new(OsRng, 16), IDS);
+kms::conformance_tests!(cached, || Cached::new(Khf::new(OsRng, &[4, 4]), 4), IDS);
+kms::conformance_tests!(
+    sealed,
+    || Sealed::new(Khf::new(OsRng, &[4, 4]), &MasterKey::random(&mut OsRng)).unwrap(),
+    IDS
+);
+kms::conformance_tests!(epoch, || Epoch::new(Khf::new(OsRng, &[4, 4])), IDS);
+kms::conformance_tests!(
+    wal,
+    || Wal::create(
+        Khf::new(OsRng, &[4, 4]),
+        Cursor::new(Vec::new()),
+        Cursor::new(Vec::new())
+    )
+    .unwrap(),
+    IDS
+);
+
+#[test]
+fn memory_derive_many_after_range() {
+    conformance::derive_many_matches_derive_after_range(&mut MemoryKms::new(OsRng), &IDS);
+}
+
+#[test]
+fn khf_derive_many_after_range() {
+    conformance::derive_many_matches_derive_after_range(&mut Khf::new(OsRng, &[4, 4]), &IDS);
+}
+
+#[test]
+fn kwt_derive_many_after_range() {
+    conformance::derive_many_matches_derive_after_range(&mut Kwt::new(OsRng, 16), &IDS);
+}
+
+#[test]
+fn cached_derive_many_after_range() {
+    let mut kms = Cached::new(Khf::new(OsRng, &[4, 4]), 4);
+    conformance::derive_many_matches_derive_after_range(&mut kms, &IDS);
+}
+
+/// An RNG that always fails.
+struct Broken;
+
+impl RngCore for Broken {
+    fn next_u32(&mut self) -> u32 {
+        panic!("RNG failed")
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        panic!("RNG failed")
+    }
+
+    fn fill_bytes(&mut self, _: &mut [u8]) {
+        panic!("RNG failed")
+    }
+
+    fn try_fill_bytes(&mut self, _: &mut [u8]) -> Result<(), rand::Error> {
+        Err(rand::Error::new("RNG failed"))
+    }
+}
+
+impl CryptoRng for Broken {}
+
+#[test]
+fn try_new_reports_rng_failure() {
+    assert!(matches!(MemoryKms::<u64>::try_new(Broken), Err(KmsError::Rng(_))));
+    assert!(matches!(Khf::try_new(Broken, &[4, 4]), Err(KmsError::Rng(_))));
+    assert!(matches!(Kwt::try_new(Broken, 16), Err(KmsError::Rng(_))));
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use std::fs;
+
+use kms::{
+    format, Epoch, FileCounter, KeyManagementScheme, Khf, KmsError, MasterKey, MemStorage,
+    MonotonicCounter, Sealed, Storage, Versioned,
+};
+use rand::rngs::OsRng;
+
+use common::Dir;
+
+fn persist(kms: &mut Epoch<Khf>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    format::encode(kms, &mut bytes).unwrap();
+    bytes
+}
+
+#[test]
+fn counter_only_moves_forward() {
+    let dir = Dir::new("counter");
+    let mut counter = FileCounter::new(dir.path("counter")).unwrap();
+    assert_eq!(counter.read().unwrap(), 0);
+
+    counter.advance(3).unwrap();
+    counter.advance(2).unwrap();
+    assert_eq!(counter.read().unwrap(), 3);
+    assert_eq!(
+        FileCounter::new(dir.path("counter"))
+            .unwrap()
+            .read()
+            .unwrap(),
+        3
+    );
+}
+
+#[test]
+fn counter_in_working_directory() {
+    let name = format!("kms-epoch-counter-{}", std::process::id());
+    let mut counter = FileCounter::new(&name).unwrap();
+    counter.advance(5).unwrap();
+    let read = FileCounter::new(&name).unwrap().read();
+    fs::remove_file(&name).unwrap();
+    assert_eq!(read.unwrap(), 5);
+}
+
+#[test]
+fn rejects_rolled_back_state() {
+    let dir = Dir::new("rollback");
+    let mut counter = FileCounter::new(dir.path("counter")).unwrap();
+
+    let mut kms = Epoch::new(Khf::new(OsRng, &[4, 4]));
+    kms.derive(7).unwrap();
+    kms.commit(OsRng).unwrap();
+    let old = persist(&mut kms);
+    kms.record(&mut counter).unwrap();
+
+    kms.revoke(7).unwrap();
+    kms.commit(OsRng).unwrap();
+    let new = persist(&mut kms);
+    kms.record(&mut counter).unwrap();
+    assert_eq!(kms.epoch(), 2);
+
+    assert!(matches!(
+        Epoch::<Khf>::load_verified(&old[..], &mut counter),
+        Err(KmsError::Rollback {
+            epoch: 1,
+            counter: 2
+        })
+    ));
+    assert_eq!(
+        Epoch::<Khf>::load_verified(&new[..], &mut counter)
+            .unwrap()
+            .epoch(),
+        2
+    );
+}
+
+#[test]
+fn catches_up_after_crash() {
+    let dir = Dir::new("crash");
+    let mut counter = FileCounter::new(dir.path("counter")).unwrap();
+
+    let mut kms = Epoch::new(Khf::new(OsRng, &[4, 4]));
+    kms.commit(OsRng).unwrap();
+    let old = persist(&mut kms);
+    kms.record(&mut counter).unwrap();
+
+    // Crash after persisting but before recording the epoch.
+    kms.commit(OsRng).unwrap();
+    let new = persist(&mut kms);
+
+    Epoch::<Khf>::load_verified(&new[..], &mut counter).unwrap();
+    assert_eq!(counter.read().unwrap(), 2);
+    assert!(Epoch::<Khf>::load_verified(&old[..], &mut counter).is_err());
+}
+
+#[test]
+fn composes_with_sealing() {
+    let dir = Dir::new("sealed");
+    let mut counter = FileCounter::new(dir.path("counter")).unwrap();
+    let master = MasterKey::random(&mut OsRng);
+
+    let mut kms = Sealed::new(Epoch::new(Khf::new(OsRng, &[4, 4])), &master).unwrap();
+    let mut copies = Vec::new();
+    for _ in 0..3 {
+        kms.commit(OsRng).unwrap();
+        let mut bytes = Vec::new();
+        kms.persist(&mut bytes).unwrap();
+        kms.inner().record(&mut counter).unwrap();
+        copies.push(bytes);
+    }
+
+    for (i, bytes) in copies.iter().enumerate() {
+        let loaded = Sealed::<Epoch<Khf>>::load_verified(&master, &bytes[..], &mut counter);
+        match loaded {
+            Ok(loaded) => assert_eq!((i, loaded.inner().epoch()), (2, 3)),
+            Err(err) => assert!(matches!(err, KmsError::Rollback { counter: 3, .. })),
+        }
+    }
+}
+
+#[test]
+fn loads_verified_from_storage() {
+    let dir = Dir::new("storage");
+    let mut counter = FileCounter::new(dir.path("counter")).unwrap();
+    let mut storage = MemStorage::new();
+    let master = MasterKey::random(&mut OsRng);
+
+    let mut kms = Sealed::new(Epoch::new(Khf::new(OsRng, &[4, 4])), &master).unwrap();
+    kms.commit(OsRng).unwrap();
+    kms.persist_to(&mut storage, "sealed").unwrap();
+    let old = storage.read("sealed").unwrap().unwrap();
+    kms.commit(OsRng).unwrap();
+    kms.persist_to(&mut storage, "sealed").unwrap();
+    kms.inner().record(&mut counter).unwrap();
+
+    let mut plain = Epoch::new(Khf::new(OsRng, &[4, 4]));
+    plain.persist_to(&mut storage, "plain").unwrap();
+    assert!(matches!(
+        Epoch::<Khf>::load_verified_from(&mut storage, "plain", &mut counter),
+        Err(KmsError::Rollback { epoch: 0, .. })
+    ));
+
+    Sealed::<Epoch<Khf>>::load_verified_from(&master, &mut storage, "sealed", &mut counter)
+        .unwrap();
+    storage.replace("sealed", &old).unwrap();
+    assert!(matches!(
+        Sealed::<Epoch<Khf>>::load_verified_from(&master, &mut storage, "sealed", &mut counter),
+        Err(KmsError::Rollback {
+            epoch: 1,
+            counter: 2
+        })
+    ));
+}
+
+#[test]
+fn is_distinct_from_inner_scheme() {
+    let mut kms = Khf::new(OsRng, &[4, 4]);
+    let mut bytes = Vec::new();
+    format::encode(&mut kms, &mut bytes).unwrap();
+    assert!(matches!(
+        format::decode::<Epoch<Khf>>(&bytes[..]),
+        Err(KmsError::Corrupted(_))
+    ));
+}
@@ -0,0 +1,129 @@
+use std::fmt::Debug;
+
+use kms::{
+    format, Key, KeyManagementScheme, Khf, KmsError, Kwt, MemStorage, MemoryKms, Storage, Versioned,
+};
+
+const IDS: [u64; 4] = [0, 1, 7, 1000];
+
+const MEMORY: [&str; 4] = [
+    "08108bd1353ffeef0b07e9aa48460b4d1bea16415c1c9190a20e7f20ebc5480c",
+    "085c0c016f1e290dc91a3f167f88b434741cfabd7d61d1aec96b3a0fabcda52c",
+    "0e590120946ec9bc9279efa80f4f37b7460796a95d8d9d7600a361218d3885bc",
+    "fb9b32ab0bd410ef5287a256dd4ee008142767abcafb309759a57118ac559263",
+];
+
+const KHF: [&str; 4] = [
+    "0488c9b51f188970e0b271cba6ec6214cf1e0cb602ef01e582aed26c6c6242a2",
+    "36b5954ba75cc49630d883a22c04dbdde496f2bb3eef60a4a56192563dcd0aa3",
+    "23b7b317119be87d52ee76075c439313cd21cae62e0de02167d8bacd4c8dad29",
+    "4a7db0964849286cbe17a980ab26d29796b5f22a41f7d89be6984c56086074bd",
+];
+
+const KWT: [&str; 4] = [
+    "9177411ba604a19b9ddd4fd77db1af784b7161b8c0dcfd9c3ccb170653462466",
+    "84a175ce4172c52d40481f948e1194f1f240944bdbfda23fb92ebdba9424ec27",
+    "2170bc2310f04cab1fa83c27bbff4f04f2a7ec4b5f1b705c73cf36332842d36b",
+    "042ce1a01acc46a3afa0da6557eb94c83d4ee3e1949aacfe76c54e02dd4aa108",
+];
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Checks the keys held by a decoded golden file, and returns it re-encoded.
+fn check<K>(mut kms: K, expected: [&str; 4]) -> Vec<u8>
+where
+    K: Versioned<KeyId = u64, Key = Key>,
+    K::Error: From<KmsError> + Debug,
+{
+    for (id, expected) in IDS.into_iter().zip(expected) {
+        assert_eq!(hex(kms.derive(id).unwrap().as_bytes()), expected);
+    }
+
+    let mut bytes = Vec::new();
+    format::encode(&mut kms, &mut bytes).unwrap();
+    bytes
+}
+
+macro_rules! golden_tests {
+    ($name:ident, $scheme:ty, $keys:expr) => {
+        mod $name {
+            use super::*;
+
+            const V0: &[u8] = include_bytes!(concat!("golden/", stringify!($name), ".v0"));
+            const V1: &[u8] = include_bytes!(concat!("golden/", stringify!($name), ".v1"));
+
+            #[test]
+            fn v1_round_trips() {
+                let kms: $scheme = format::decode(V1).unwrap();
+                assert_eq!(check(kms, $keys), V1);
+            }
+
+            #[test]
+            fn v0_migrates_to_v1() {
+                let kms: $scheme = format::decode_v0(V0).unwrap();
+                assert_eq!(check(kms, $keys), V1);
+            }
+
+            #[test]
+            fn v0_needs_opt_in() {
+                assert!(corrupted::<$scheme>(V0));
+            }
+        }
+    };
+}
+
+golden_tests!(memory, MemoryKms, MEMORY);
+golden_tests!(khf, Khf, KHF);
+golden_tests!(kwt, Kwt, KWT);
+
+fn corrupted<K: Versioned<Error = KmsError>>(bytes: &[u8]) -> bool {
+    matches!(format::decode::<K>(bytes), Err(KmsError::Corrupted(_)))
+}
+
+#[test]
+fn rejects_tampering() {
+    let golden = include_bytes!("golden/khf.v1");
+    for at in [0, 4, 10, 20, golden.len() - 1] {
+        let mut bytes = golden.to_vec();
+        bytes[at] ^= 1;
+        assert!(
+            format::decode::<Khf>(&bytes[..]).is_err(),
+            "flipped byte {at}"
+        );
+    }
+    assert!(corrupted::<Khf>(&golden[..golden.len() - 1]));
+}
+
+#[test]
+fn rejects_other_schemes() {
+    assert!(corrupted::<Kwt>(include_bytes!("golden/khf.v1")));
+    assert!(corrupted::<Khf>(include_bytes!("golden/memory.v1")));
+}
+
+#[test]
+fn rejects_future_versions() {
+    let mut bytes = include_bytes!("golden/kwt.v1").to_vec();
+    bytes[4] = 2;
+    let len = bytes.len() - 32;
+    let checksum = <sha2::Sha256 as sha2::Digest>::digest(&bytes[..len]);
+    bytes[len..].copy_from_slice(&checksum);
+    assert!(corrupted::<Kwt>(&bytes));
+}
+
+#[test]
+fn storage_round_trips() {
+    let mut kms: Khf = format::decode(&include_bytes!("golden/khf.v1")[..]).unwrap();
+    let mut storage = MemStorage::new();
+    kms.persist_to(&mut storage, "khf").unwrap();
+    assert_eq!(
+        storage.read("khf").unwrap().unwrap(),
+        include_bytes!("golden/khf.v1")
+    );
+
+    let mut loaded = Khf::load_from(&mut storage, "khf").unwrap();
+    for id in IDS {
+        assert_eq!(loaded.derive(id).unwrap(), kms.derive(id).unwrap());
+    }
+}
@@ -0,0 +1,144 @@
+use std::{collections::HashSet, ops::Range};
+
+use kms::{KeyManagementScheme, Khf, KmsError, Persist, UpdateRange};
+use rand::rngs::OsRng;
+
+/// The last leaf of the last whole tree when each tree has 7 leaves, since 2^64 = 2 (mod 7).
+const LAST: u64 = u64::MAX - 2;
+
+#[test]
+fn rejects_leaves_of_partial_tree() {
+    let mut kms = Khf::new(OsRng, &[7]);
+    let first = kms.derive(0).unwrap();
+    let last = kms.derive(LAST).unwrap();
+    kms.commit(OsRng).unwrap();
+
+    for leaf in [LAST + 1, u64::MAX] {
+        assert!(matches!(kms.derive(leaf), Err(KmsError::UnknownKey)));
+        assert!(matches!(kms.update(leaf), Err(KmsError::UnknownKey)));
+        assert!(matches!(kms.revoke(leaf), Err(KmsError::UnknownKey)));
+    }
+    assert!(matches!(
+        kms.derive_many([0, u64::MAX]),
+        Err(KmsError::UnknownKey)
+    ));
+    assert!(kms.commit(OsRng).unwrap().is_empty());
+    assert_eq!(kms.derive(0).unwrap(), first);
+    assert_eq!(kms.derive(LAST).unwrap(), last);
+}
+
+#[test]
+fn updates_last_whole_tree() {
+    let mut kms = Khf::new(OsRng, &[7]);
+    let first = kms.derive(0).unwrap();
+    kms.update(LAST).unwrap();
+
+    let changes = kms.commit(OsRng).unwrap();
+    let leaves: Vec<u64> = changes.iter().map(|(leaf, _)| *leaf).collect();
+    assert_eq!(leaves, (LAST - 6..=LAST).collect::<Vec<_>>());
+    assert_eq!(kms.derive(0).unwrap(), first);
+}
+
+#[test]
+fn rejects_persisted_partial_tree() {
+    let mut bytes = Vec::new();
+    for n in [1, 7, 1, u64::MAX / 7] {
+        bytes.extend_from_slice(&n.to_le_bytes());
+    }
+    bytes.extend_from_slice(&[0; 32]);
+    assert!(matches!(Khf::load(&bytes[..]), Err(KmsError::Corrupted(_))));
+}
+
+/// Checks `update_range(range)` against updating each id of `range` in turn, starting from the
+/// same committed forest with `prepare` applied to each copy.
+fn update_range_matches_updates(fanouts: &[u64], prepare: impl Fn(&mut Khf), range: Range<u64>) {
+    // The ids around the range that the forest covers, deriving the roots of their trees.
+    let mut base = Khf::new(OsRng, fanouts);
+    let probes: Vec<u64> = (range.start.saturating_sub(20)..range.end.saturating_add(20))
+        .filter(|id| base.derive(*id).is_ok())
+        .collect();
+    let mut bytes = Vec::new();
+    base.persist(&mut bytes).unwrap();
+
+    let mut ranged = Khf::load(&bytes[..]).unwrap();
+    let mut single = Khf::load(&bytes[..]).unwrap();
+    prepare(&mut ranged);
+    prepare(&mut single);
+    let ranged_before = ranged.derive_many(probes.iter().copied()).unwrap();
+    let single_before = single.derive_many(probes.iter().copied()).unwrap();
+
+    ranged.update_range(range.clone()).unwrap();
+    for id in range.clone() {
+        single.update(id).unwrap();
+    }
+
+    // Exactly the ids in the range change, each to a key of its own.
+    let ranged_after = ranged.derive_many(probes.iter().copied()).unwrap();
+    let single_after = single.derive_many(probes.iter().copied()).unwrap();
+    for (i, id) in probes.iter().enumerate() {
+        assert_eq!(ranged_after[i] != ranged_before[i], range.contains(id));
+        assert_eq!(single_after[i] != single_before[i], range.contains(id));
+    }
+    let fresh: HashSet<_> = ranged_after
+        .iter()
+        .map(|key| key.as_bytes().to_vec())
+        .collect();
+    assert_eq!(fresh.len(), probes.len());
+
+    // Both commits re-key the same leaves, to the keys derived afterwards.
+    let ranged_changes = ranged.commit(OsRng).unwrap();
+    let single_changes = single.commit(OsRng).unwrap();
+    let leaves = |changes: &[(u64, Option<kms::Key>)]| -> Vec<u64> {
+        changes.iter().map(|(leaf, _)| *leaf).collect()
+    };
+    assert_eq!(leaves(&ranged_changes), leaves(&single_changes));
+    assert!(range
+        .clone()
+        .all(|id| leaves(&ranged_changes).contains(&id)));
+    for (leaf, key) in ranged_changes {
+        assert_eq!(ranged.derive(leaf).unwrap(), key.unwrap());
+    }
+}
+
+#[test]
+fn updates_unaligned_range() {
+    update_range_matches_updates(&[2, 4], |_| {}, 3..6);
+    update_range_matches_updates(&[2, 4], |_| {}, 5..7);
+    update_range_matches_updates(&[2, 4], |_| {}, 1..2);
+    update_range_matches_updates(&[2, 4], |_| {}, 4..4);
+}
+
+#[test]
+fn updates_range_crossing_trees() {
+    update_range_matches_updates(&[2, 4], |_| {}, 6..19);
+    update_range_matches_updates(&[2, 4], |_| {}, 8..24);
+    update_range_matches_updates(&[7], |_| {}, 5..30);
+}
+
+#[test]
+fn updates_range_inside_fragments() {
+    let fragment = |kms: &mut Khf| kms.update_range(8..16).unwrap();
+    update_range_matches_updates(&[2, 4], fragment, 9..11);
+    update_range_matches_updates(&[2, 4], fragment, 12..16);
+
+    let leaf = |kms: &mut Khf| drop(kms.update(10).unwrap());
+    update_range_matches_updates(&[2, 4], leaf, 8..16);
+    update_range_matches_updates(&[2, 4], leaf, 10..11);
+    update_range_matches_updates(&[2, 4], leaf, 11..13);
+}
+
+#[test]
+fn updates_range_near_end() {
+    update_range_matches_updates(&[2, 4], |_| {}, u64::MAX - 12..u64::MAX);
+    update_range_matches_updates(&[2, 4], |_| {}, u64::MAX - 8..u64::MAX - 7);
+    update_range_matches_updates(&[7], |_| {}, LAST - 9..LAST + 1);
+
+    let mut kms = Khf::new(OsRng, &[7]);
+    let key = kms.derive(LAST).unwrap();
+    assert!(matches!(
+        kms.update_range(LAST..u64::MAX),
+        Err(KmsError::UnknownKey)
+    ));
+    assert_eq!(kms.derive(LAST).unwrap(), key);
+    assert!(kms.commit(OsRng).unwrap().is_empty());
+}
@@ -0,0 +1,251 @@
+mod common;
+
+use std::{cell::Cell, fs, rc::Rc};
+
+use kms::{FileStorage, Khf, KmsError, KvStore, Kwt, Storage, Versioned};
+use rand::rngs::OsRng;
+
+use common::Dir;
+
+fn storage(dir: &Dir) -> FileStorage {
+    FileStorage::new(dir.path("state")).unwrap()
+}
+
+fn fill<K, S>(store: &mut KvStore<K, S>)
+where
+    K: Versioned<KeyId = u64, Key = kms::Key, Error = KmsError>,
+    S: Storage,
+{
+    for i in 0..20 {
+        store.put(
+            format!("key {i}").as_bytes(),
+            format!("value {i}").as_bytes(),
+        );
+    }
+    store.flush(OsRng).unwrap();
+}
+
+fn get<K, S>(store: &mut KvStore<K, S>, key: &str) -> Option<String>
+where
+    K: Versioned<KeyId = u64, Key = kms::Key, Error = KmsError>,
+    S: Storage,
+{
+    let value = store.get(key.as_bytes()).unwrap();
+    value.map(|value| String::from_utf8(value).unwrap())
+}
+
+#[test]
+fn round_trip() {
+    let dir = Dir::new("round-trip");
+    let mut store = KvStore::create(
+        Khf::new(OsRng, &[4, 4]),
+        storage(&dir),
+        "kms",
+        dir.path("log"),
+    )
+    .unwrap();
+    fill(&mut store);
+
+    store.put(b"key 3", b"changed");
+    store.delete(b"key 4");
+    store.delete(b"missing");
+    assert_eq!(get(&mut store, "key 3").as_deref(), Some("changed"));
+    assert_eq!(get(&mut store, "key 4"), None);
+    store.flush(OsRng).unwrap();
+    drop(store);
+
+    let mut store = KvStore::<Khf, _>::open(storage(&dir), "kms", dir.path("log")).unwrap();
+    for i in 0..20 {
+        let expected = match i {
+            3 => Some("changed".to_string()),
+            4 => None,
+            _ => Some(format!("value {i}")),
+        };
+        assert_eq!(get(&mut store, &format!("key {i}")), expected);
+    }
+
+    store.put(b"key 4", b"restored");
+    store.put(b"key 20", b"new");
+    store.flush(OsRng).unwrap();
+    assert_eq!(get(&mut store, "key 4").as_deref(), Some("restored"));
+    assert_eq!(get(&mut store, "key 20").as_deref(), Some("new"));
+}
+
+fn deleted_values_stay_deleted<K>(dir: Dir, kms: K)
+where
+    K: Versioned<KeyId = u64, Key = kms::Key, Error = KmsError>,
+{
+    let mut store = KvStore::create(kms, storage(&dir), "kms", dir.path("log")).unwrap();
+    fill(&mut store);
+    let log = fs::read(dir.path("log")).unwrap();
+
+    store.delete(b"key 5");
+    store.put(b"key 6", b"changed");
+    store.flush(OsRng).unwrap();
+    drop(store);
+
+    // Restoring the old log does not bring back what the scheme has forgotten.
+    fs::write(dir.path("log"), log).unwrap();
+    let mut store = KvStore::<K, _>::open(storage(&dir), "kms", dir.path("log")).unwrap();
+    assert_eq!(get(&mut store, "key 5"), None);
+    assert_eq!(get(&mut store, "key 6"), None);
+}
+
+#[test]
+fn khf_deleted_values_stay_deleted() {
+    deleted_values_stay_deleted(Dir::new("khf"), Khf::new(OsRng, &[4, 4]));
+}
+
+#[test]
+fn kwt_deleted_values_stay_deleted() {
+    deleted_values_stay_deleted(Dir::new("kwt"), Kwt::new(OsRng, 4));
+}
+
+#[test]
+fn compaction_drops_dead_records() {
+    let dir = Dir::new("compact");
+    let mut store = KvStore::create(
+        Khf::new(OsRng, &[4, 4]),
+        storage(&dir),
+        "kms",
+        dir.path("log"),
+    )
+    .unwrap();
+    fill(&mut store);
+    for i in 0..10 {
+        store.delete(format!("key {i}").as_bytes());
+    }
+    store.flush(OsRng).unwrap();
+
+    let len = fs::metadata(dir.path("log")).unwrap().len();
+    store.compact(OsRng).unwrap();
+    assert!(fs::metadata(dir.path("log")).unwrap().len() < len / 2);
+    drop(store);
+
+    let mut store = KvStore::<Khf, _>::open(storage(&dir), "kms", dir.path("log")).unwrap();
+    for i in 0..20 {
+        let expected = (i >= 10).then(|| format!("value {i}"));
+        assert_eq!(get(&mut store, &format!("key {i}")), expected);
+    }
+}
+
+#[test]
+fn reopening_keeps_committed_deletions() {
+    let dir = Dir::new("reopen");
+    let mut store = KvStore::create(
+        Khf::new(OsRng, &[4, 4]),
+        storage(&dir),
+        "kms",
+        dir.path("log"),
+    )
+    .unwrap();
+    fill(&mut store);
+    store.delete(b"key 4");
+    store.flush(OsRng).unwrap();
+    drop(store);
+
+    // The deletion was committed, so reopening revokes nothing and the flush re-encrypts nothing.
+    let len = fs::metadata(dir.path("log")).unwrap().len();
+    let mut store = KvStore::<Khf, _>::open(storage(&dir), "kms", dir.path("log")).unwrap();
+    store.flush(OsRng).unwrap();
+    assert_eq!(fs::metadata(dir.path("log")).unwrap().len(), len);
+    assert_eq!(get(&mut store, "key 4"), None);
+    assert_eq!(get(&mut store, "key 5").as_deref(), Some("value 5"));
+}
+
+#[test]
+fn interrupted_flush_keeps_old_values() {
+    let dir = Dir::new("interrupted");
+    let mut store = KvStore::create(
+        Khf::new(OsRng, &[4, 4]),
+        storage(&dir),
+        "kms",
+        dir.path("log"),
+    )
+    .unwrap();
+    fill(&mut store);
+    let state = storage(&dir).read("kms").unwrap().unwrap();
+
+    store.put(b"key 1", b"changed");
+    store.delete(b"key 2");
+    store.flush(OsRng).unwrap();
+    drop(store);
+
+    // As if the flush crashed after appending to the log, but before persisting its commit.
+    storage(&dir).replace("kms", &state).unwrap();
+    let mut store = KvStore::<Khf, _>::open(storage(&dir), "kms", dir.path("log")).unwrap();
+    assert_eq!(get(&mut store, "key 1").as_deref(), Some("value 1"));
+    assert_eq!(get(&mut store, "key 2"), None);
+
+    store.flush(OsRng).unwrap();
+    drop(store);
+    let mut store = KvStore::<Khf, _>::open(storage(&dir), "kms", dir.path("log")).unwrap();
+    for i in (0..20).filter(|i| *i != 2) {
+        assert_eq!(
+            get(&mut store, &format!("key {i}")),
+            Some(format!("value {i}"))
+        );
+    }
+}
+
+/// Storage that fails to replace the blob named by its switch, while the switch is set.
+struct Failing {
+    inner: FileStorage,
+    fail: Rc<Cell<Option<&'static str>>>,
+}
+
+impl Storage for Failing {
+    fn replace(&mut self, name: &str, bytes: &[u8]) -> Result<(), KmsError> {
+        if self.fail.get() == Some(name) {
+            return Err(KmsError::backend("crashed"));
+        }
+        self.inner.replace(name, bytes)
+    }
+
+    fn read(&mut self, name: &str) -> Result<Option<Vec<u8>>, KmsError> {
+        self.inner.read(name)
+    }
+
+    fn discard(&mut self, name: &str) -> Result<(), KmsError> {
+        self.inner.discard(name)
+    }
+}
+
+#[test]
+fn failed_flush_keeps_changes() {
+    // The journal fails before the scheme is committed, and the state once it has been.
+    for blob in ["kms.journal", "kms"] {
+        let dir = Dir::new(blob);
+        let fail = Rc::new(Cell::new(None));
+        let failing = Failing {
+            inner: storage(&dir),
+            fail: fail.clone(),
+        };
+        let mut store =
+            KvStore::create(Khf::new(OsRng, &[4, 4]), failing, "kms", dir.path("log")).unwrap();
+        fill(&mut store);
+
+        store.put(b"key 1", b"changed");
+        store.delete(b"key 2");
+        store.put(b"key 20", b"new");
+        fail.set(Some(blob));
+        assert!(store.flush(OsRng).is_err());
+        assert_eq!(get(&mut store, "key 1").as_deref(), Some("changed"));
+        assert_eq!(get(&mut store, "key 2"), None);
+
+        fail.set(None);
+        store.flush(OsRng).unwrap();
+        drop(store);
+
+        let mut store = KvStore::<Khf, _>::open(storage(&dir), "kms", dir.path("log")).unwrap();
+        for i in 0..21 {
+            let expected = match i {
+                1 => Some("changed".to_string()),
+                2 => None,
+                20 => Some("new".to_string()),
+                _ => Some(format!("value {i}")),
+            };
+            assert_eq!(get(&mut store, &format!("key {i}")), expected, "{blob}");
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
+};
+use kms::{
+    BlockStore, Key, KeyManagementScheme, Khf, KmsError, Kwt, MemStorage, Reencryptor, Storage,
+    Versioned,
+};
+use rand::rngs::OsRng;
+
+const BLOCKS: u64 = 40;
+
+/// Blocks kept in memory, each sealed with XChaCha20-Poly1305 and bound to its number.
+#[derive(Default)]
+struct MemBlocks(BTreeMap<u64, Vec<u8>>);
+
+impl BlockStore for MemBlocks {
+    fn blocks(&mut self) -> Result<Vec<u64>, KmsError> {
+        Ok(self.0.keys().copied().collect())
+    }
+
+    fn read(&mut self, block: u64) -> Result<Vec<u8>, KmsError> {
+        self.0.get(&block).cloned().ok_or(KmsError::UnknownKey)
+    }
+
+    fn write(&mut self, block: u64, bytes: &[u8]) -> Result<(), KmsError> {
+        self.0.insert(block, bytes.to_vec());
+        Ok(())
+    }
+
+    fn encrypt(&self, key: &Key, block: u64, plaintext: &[u8]) -> Result<Vec<u8>, KmsError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = block.to_le_bytes();
+        let mut bytes = nonce.to_vec();
+        bytes.extend(
+            XChaCha20Poly1305::new(key.as_bytes().into())
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: plaintext,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| KmsError::backend("encryption failed"))?,
+        );
+        Ok(bytes)
+    }
+
+    fn decrypt(&self, key: &Key, block: u64, ciphertext: &[u8]) -> Result<Vec<u8>, KmsError> {
+        let (nonce, msg) = ciphertext.split_at(24);
+        let aad = block.to_le_bytes();
+        XChaCha20Poly1305::new(key.as_bytes().into())
+            .decrypt(XNonce::from_slice(nonce), Payload { msg, aad: &aad })
+            .map_err(|_| KmsError::backend("decryption failed"))
+    }
+}
+
+/// Storage that fails to replace a blob once it has been replaced a given number of times.
+#[derive(Default)]
+struct Flaky {
+    inner: MemStorage,
+    fail: Option<(String, usize)>,
+}
+
+impl Flaky {
+    fn fail(&mut self, name: &str, after: usize) {
+        self.fail = Some((name.into(), after));
+    }
+}
+
+impl Storage for Flaky {
+    fn replace(&mut self, name: &str, bytes: &[u8]) -> Result<(), KmsError> {
+        if let Some((failing, after)) = &mut self.fail {
+            if failing == name {
+                if *after == 0 {
+                    return Err(KmsError::backend("crashed"));
+                }
+                *after -= 1;
+            }
+        }
+        self.inner.replace(name, bytes)
+    }
+
+    fn read(&mut self, name: &str) -> Result<Option<Vec<u8>>, KmsError> {
+        self.inner.read(name)
+    }
+
+    fn discard(&mut self, name: &str) -> Result<(), KmsError> {
+        self.inner.discard(name)
+    }
+}
+
+fn contents(block: u64, round: u64) -> Vec<u8> {
+    format!("block {block} written in round {round}").into_bytes()
+}
+
+/// Writes every block under its current key, and persists the scheme.
+fn setup<K>(mut kms: K) -> (K, MemBlocks, Reencryptor<Flaky>)
+where
+    K: Versioned<KeyId = u64, Key = Key, Error = KmsError>,
+{
+    let mut blocks = MemBlocks::default();
+    let mut reencryptor = Reencryptor::new(Flaky::default(), "state");
+    reencryptor.commit(&mut kms, &mut blocks, OsRng).unwrap();
+
+    for block in 0..BLOCKS {
+        let key = kms.derive(block).unwrap();
+        let bytes = blocks.encrypt(&key, block, &contents(block, 0)).unwrap();
+        blocks.write(block, &bytes).unwrap();
+    }
+    reencryptor.commit(&mut kms, &mut blocks, OsRng).unwrap();
+    (kms, blocks, reencryptor)
+}
+
+/// Rewrites some blocks under updated keys, as a client would before committing.
+fn overwrite(
+    kms: &mut impl KeyManagementScheme<KeyId = u64, Key = Key, Error = KmsError>,
+    blocks: &mut MemBlocks,
+) {
+    for block in [3, 17, 18] {
+        let key = kms.update(block).unwrap();
+        let bytes = blocks.encrypt(&key, block, &contents(block, 1)).unwrap();
+        blocks.write(block, &bytes).unwrap();
+    }
+}
+
+/// Checks that every block holds the expected contents under its current key.
+fn check(
+    kms: &mut impl KeyManagementScheme<KeyId = u64, Key = Key, Error = KmsError>,
+    blocks: &mut MemBlocks,
+) {
+    for block in 0..BLOCKS {
+        let key = kms.derive(block).unwrap();
+        let bytes = blocks.read(block).unwrap();
+        let round = u64::from([3, 17, 18].contains(&block));
+        assert_eq!(
+            blocks.decrypt(&key, block, &bytes).unwrap(),
+            contents(block, round)
+        );
+    }
+}
+
+fn reencrypts<K>(kms: K)
+where
+    K: Versioned<KeyId = u64, Key = Key, Error = KmsError>,
+{
+    let (mut kms, mut blocks, mut reencryptor) = setup(kms);
+    overwrite(&mut kms, &mut blocks);
+    reencryptor.commit(&mut kms, &mut blocks, OsRng).unwrap();
+    check(&mut kms, &mut blocks);
+
+    let mut loaded = K::load_from(reencryptor.storage(), "state").unwrap();
+    check(&mut loaded, &mut blocks);
+    assert!(reencryptor
+        .storage()
+        .read("state.journal")
+        .unwrap()
+        .is_none());
+}
+
+fn resumes(after: usize) {
+    let (mut kms, mut blocks, mut reencryptor) = setup(Khf::new(OsRng, &[4, 4]));
+    overwrite(&mut kms, &mut blocks);
+    reencryptor.storage().fail("state.cursor", after);
+    assert!(reencryptor.commit(&mut kms, &mut blocks, OsRng).is_err());
+    drop(kms);
+
+    reencryptor.storage().fail = None;
+    let mut kms = Khf::load_from(reencryptor.storage(), "state").unwrap();
+    reencryptor.resume(&mut kms, &mut blocks).unwrap();
+    check(&mut kms, &mut blocks);
+    assert!(reencryptor
+        .storage()
+        .read("state.journal")
+        .unwrap()
+        .is_none());
+    assert_eq!(reencryptor.resume(&mut kms, &mut blocks).unwrap(), 0);
+}
+
+#[test]
+fn khf_reencrypts_changed_blocks() {
+    reencrypts(Khf::new(OsRng, &[4, 4]));
+}
+
+#[test]
+fn kwt_reencrypts_changed_blocks() {
+    reencrypts(Kwt::new(OsRng, 4));
+}
+
+#[test]
+fn resumes_after_crash() {
+    // Khf re-keys every leaf of a tree it consolidates, so the rewrite spans several blocks.
+    for after in [0, 1, 5] {
+        resumes(after);
+    }
+}
+
+#[test]
+fn discards_unpersisted_commit() {
+    let (mut kms, mut blocks, mut reencryptor) = setup(Khf::new(OsRng, &[4, 4]));
+    overwrite(&mut kms, &mut blocks);
+    reencryptor.storage().fail("state", 0);
+    assert!(reencryptor.commit(&mut kms, &mut blocks, OsRng).is_err());
+    assert!(reencryptor
+        .storage()
+        .read("state.journal")
+        .unwrap()
+        .is_some());
+
+    // The updates were never committed, so the overwritten blocks are lost along with them, both
+    // in the scheme as restored in memory and as reloaded from storage.
+    reencryptor.storage().fail = None;
+    let kept = (0..BLOCKS).filter(|block| ![3, 17, 18].contains(block));
+    assert_eq!(
+        kms.derive_many(kept.clone()).unwrap(),
+        Khf::load_from(reencryptor.storage(), "state")
+            .unwrap()
+            .derive_many(kept)
+            .unwrap()
+    );
+    let mut kms = Khf::load_from(reencryptor.storage(), "state").unwrap();
+    assert_eq!(reencryptor.resume(&mut kms, &mut blocks).unwrap(), 0);
+    assert!(reencryptor
+        .storage()
+        .read("state.journal")
+        .unwrap()
+        .is_none());
+    for block in (0..BLOCKS).filter(|block| ![3, 17, 18].contains(block)) {
+        let key = kms.derive(block).unwrap();
+        let bytes = blocks.read(block).unwrap();
+        assert_eq!(
+            blocks.decrypt(&key, block, &bytes).unwrap(),
+            contents(block, 0)
+        );
+    }
+}
+
+#[test]
+fn restores_scheme_if_journal_fails() {
+    let (mut kms, mut blocks, mut reencryptor) = setup(Khf::new(OsRng, &[4, 4]));
+    let committed = kms.derive_many(0..BLOCKS).unwrap();
+    overwrite(&mut kms, &mut blocks);
+    reencryptor.storage().fail("state.journal", 0);
+    assert!(reencryptor.commit(&mut kms, &mut blocks, OsRng).is_err());
+    assert_eq!(kms.derive_many(0..BLOCKS).unwrap(), committed);
+
+    reencryptor.storage().fail = None;
+    assert_eq!(reencryptor.commit(&mut kms, &mut blocks, OsRng).unwrap(), 0);
+    assert_eq!(kms.derive_many(0..BLOCKS).unwrap(), committed);
+}
+
+#[test]
+fn fails_on_underivable_blocks() {
+    // With 7 leaves per tree, the last leaves of the `u64` range are in no whole tree.
+    let (mut kms, mut blocks, mut reencryptor) = setup(Khf::new(OsRng, &[7]));
+    blocks.write(u64::MAX, &[0; 64]).unwrap();
+    overwrite(&mut kms, &mut blocks);
+    assert!(matches!(
+        reencryptor.commit(&mut kms, &mut blocks, OsRng),
+        Err(KmsError::UnknownKey)
+    ));
+    assert!(reencryptor
+        .storage()
+        .read("state.journal")
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn leaves_revoked_blocks() {
+    let (mut kms, mut blocks, mut reencryptor) = setup(Khf::new(OsRng, &[4, 4]));
+    overwrite(&mut kms, &mut blocks);
+    kms.revoke(5).unwrap();
+    let old = blocks.read(5).unwrap();
+    reencryptor.commit(&mut kms, &mut blocks, OsRng).unwrap();
+
+    assert_eq!(blocks.read(5).unwrap(), old);
+    let key = kms.derive(5).unwrap();
+    assert!(blocks.decrypt(&key, 5, &old).is_err());
+    for block in (0..BLOCKS).filter(|block| *block != 5) {
+        let key = kms.derive(block).unwrap();
+        let round = u64::from([3, 17, 18].contains(&block));
+        let bytes = blocks.read(block).unwrap();
+        assert_eq!(
+            blocks.decrypt(&key, block, &bytes).unwrap(),
+            contents(block, round)
+        );
+    }
+}
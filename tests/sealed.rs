@@ -0,0 +1,219 @@
+use std::{env, fs, io};
+
+use kms::{
+    Key, KeyManagementScheme, Khf, KmsError, MasterKey, MemStorage, Passphrase, Sealed, Storage,
+};
+use rand::rngs::OsRng;
+
+const IDS: [u64; 4] = [0, 1, 7, 1000];
+const MASTER: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+fn master_bytes() -> [u8; 32] {
+    core::array::from_fn(|i| i as u8)
+}
+
+fn master() -> MasterKey {
+    MasterKey::new(Key::new(master_bytes()))
+}
+
+/// Commits keys for every id and returns the sealed state along with the keys.
+fn sealed() -> (Vec<u8>, Vec<Key>) {
+    let mut kms = Sealed::new(Khf::new(OsRng, &[4, 4]), &master()).unwrap();
+    let keys = IDS.iter().map(|id| kms.derive(*id).unwrap()).collect();
+    kms.commit(OsRng).unwrap();
+
+    let mut bytes = Vec::new();
+    kms.persist(&mut bytes).unwrap();
+    (bytes, keys)
+}
+
+#[test]
+fn round_trips() {
+    let (bytes, keys) = sealed();
+    let mut kms = Sealed::<Khf>::load(&master(), &bytes[..]).unwrap();
+    for (id, key) in IDS.iter().zip(keys) {
+        assert_eq!(kms.derive(*id).unwrap(), key);
+    }
+}
+
+#[test]
+fn hides_keys() {
+    let (bytes, keys) = sealed();
+    let mut kms = Sealed::<Khf>::load(&master(), &bytes[..])
+        .unwrap()
+        .into_inner();
+
+    let mut plain = Vec::new();
+    kms::format::encode(&mut kms, &mut plain).unwrap();
+    for key in keys {
+        assert!(!bytes
+            .windows(key.as_bytes().len())
+            .any(|w| w == key.as_bytes()));
+    }
+    assert!(!bytes.windows(plain.len()).any(|w| w == plain));
+}
+
+#[test]
+fn rejects_tampering() {
+    let (bytes, _) = sealed();
+    for at in 0..bytes.len() {
+        let mut tampered = bytes.clone();
+        tampered[at] ^= 0x80;
+        assert!(
+            matches!(
+                Sealed::<Khf>::load(&master(), &tampered[..]),
+                Err(KmsError::Corrupted(_))
+            ),
+            "flipped byte {at}"
+        );
+    }
+
+    let truncated = &bytes[..bytes.len() - 1];
+    assert!(Sealed::<Khf>::load(&master(), truncated).is_err());
+}
+
+#[test]
+fn rejects_other_master_keys() {
+    let (bytes, _) = sealed();
+    let other = MasterKey::random(&mut OsRng);
+    assert!(matches!(
+        Sealed::<Khf>::load(&other, &bytes[..]),
+        Err(KmsError::Corrupted(_))
+    ));
+}
+
+#[test]
+fn persists_to_storage() {
+    let mut kms = Sealed::new(Khf::new(OsRng, &[4, 4]), &master()).unwrap();
+    let key = kms.derive(7).unwrap();
+    kms.commit(OsRng).unwrap();
+
+    let mut storage = MemStorage::new();
+    kms.persist_to(&mut storage, "khf").unwrap();
+    assert!(storage.read("khf").unwrap().is_some());
+
+    let mut loaded = Sealed::<Khf>::load_from(&master(), &mut storage, "khf").unwrap();
+    assert_eq!(loaded.derive(7).unwrap(), key);
+}
+
+#[test]
+fn reads_master_keys() {
+    let (bytes, _) = sealed();
+    let dir = env::temp_dir().join(format!("kms-sealed-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let hex = dir.join("hex");
+    fs::write(&hex, format!("{MASTER}\n")).unwrap();
+    assert!(Sealed::<Khf>::load(&MasterKey::from_file(&hex).unwrap(), &bytes[..]).is_ok());
+
+    let raw = dir.join("raw");
+    fs::write(&raw, master_bytes()).unwrap();
+    assert!(Sealed::<Khf>::load(&MasterKey::from_file(&raw).unwrap(), &bytes[..]).is_ok());
+
+    let short = dir.join("short");
+    fs::write(&short, &MASTER[2..]).unwrap();
+    assert!(MasterKey::from_file(&short).is_err());
+
+    env::set_var("KMS_SEALED_TEST_MASTER", MASTER.to_uppercase());
+    let master = MasterKey::from_env("KMS_SEALED_TEST_MASTER").unwrap();
+    assert!(Sealed::<Khf>::load(&master, &bytes[..]).is_ok());
+    assert!(MasterKey::from_env("KMS_SEALED_TEST_UNSET").is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+fn passphrase(passphrase: &str) -> Passphrase {
+    Passphrase::with_costs(passphrase, 64, 1, 1).unwrap()
+}
+
+#[test]
+fn unlocks_with_passphrase() {
+    let mut kms = Sealed::new(Khf::new(OsRng, &[4, 4]), &passphrase("hunter2")).unwrap();
+    let key = kms.derive(7).unwrap();
+    kms.commit(OsRng).unwrap();
+
+    let mut bytes = Vec::new();
+    kms.persist(&mut bytes).unwrap();
+
+    // The costs are read from the header rather than from the passphrase.
+    let costlier = Passphrase::with_costs("hunter2", 128, 2, 1).unwrap();
+    let mut loaded = Sealed::<Khf>::load(&costlier, &bytes[..]).unwrap();
+    assert_eq!(loaded.derive(7).unwrap(), key);
+
+    assert!(matches!(
+        Sealed::<Khf>::load(&passphrase("hunter3"), &bytes[..]),
+        Err(KmsError::Corrupted(_))
+    ));
+    assert!(Sealed::<Khf>::load(&master(), &bytes[..]).is_err());
+    assert!(Sealed::<Khf>::load(&passphrase("hunter2"), &sealed().0[..]).is_err());
+
+    // Tampering with the salt derives another key.
+    let mut tampered = bytes.clone();
+    tampered[8] ^= 1;
+    assert!(Sealed::<Khf>::load(&passphrase("hunter2"), &tampered[..]).is_err());
+}
+
+#[test]
+fn rejects_excessive_costs() {
+    let mut kms = Sealed::new(Khf::new(OsRng, &[4, 4]), &passphrase("hunter2")).unwrap();
+    kms.commit(OsRng).unwrap();
+    let mut bytes = Vec::new();
+    kms.persist(&mut bytes).unwrap();
+
+    // The memory cost, iterations, and parallelism follow the magic, version, tag, and salt.
+    for at in [23, 27, 31] {
+        let mut tampered = bytes.clone();
+        tampered[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            Sealed::<Khf>::load(&passphrase("hunter2"), &tampered[..]),
+            Err(KmsError::Corrupted(_))
+        ));
+    }
+
+    // Costs above the limits are accepted up to the passphrase's own.
+    let costly = Passphrase::with_costs("hunter2", 64, 65, 1).unwrap();
+    let mut kms = Sealed::new(Khf::new(OsRng, &[4, 4]), &costly).unwrap();
+    let key = kms.derive(7).unwrap();
+    kms.commit(OsRng).unwrap();
+    bytes.clear();
+    kms.persist(&mut bytes).unwrap();
+    assert!(matches!(
+        Sealed::<Khf>::load(&passphrase("hunter2"), &bytes[..]),
+        Err(KmsError::Corrupted(_))
+    ));
+    let mut loaded = Sealed::<Khf>::load(&costly, &bytes[..]).unwrap();
+    assert_eq!(loaded.derive(7).unwrap(), key);
+}
+
+#[test]
+fn rejects_invalid_costs() {
+    for (m_cost, t_cost, p_cost) in [(64, 0, 1), (64, 1, 0), (1, 1, 1)] {
+        assert!(matches!(
+            Passphrase::with_costs("hunter2", m_cost, t_cost, p_cost),
+            Err(KmsError::Io(err)) if err.kind() == io::ErrorKind::InvalidInput
+        ));
+    }
+}
+
+#[test]
+fn rekeys_without_rotating_keys() {
+    let (bytes, keys) = sealed();
+    let mut kms = Sealed::<Khf>::load(&master(), &bytes[..]).unwrap();
+    kms.rekey(&passphrase("correct horse")).unwrap();
+
+    let mut rekeyed = Vec::new();
+    kms.persist(&mut rekeyed).unwrap();
+    assert!(Sealed::<Khf>::load(&master(), &rekeyed[..]).is_err());
+
+    let mut kms = Sealed::<Khf>::load(&passphrase("correct horse"), &rekeyed[..]).unwrap();
+    kms.rekey(&passphrase("battery staple")).unwrap();
+    rekeyed.clear();
+    kms.persist(&mut rekeyed).unwrap();
+    assert!(Sealed::<Khf>::load(&passphrase("correct horse"), &rekeyed[..]).is_err());
+
+    let mut kms = Sealed::<Khf>::load(&passphrase("battery staple"), &rekeyed[..]).unwrap();
+    for (id, key) in IDS.iter().zip(keys) {
+        assert_eq!(kms.derive(*id).unwrap(), key);
+    }
+    assert!(kms.commit(OsRng).unwrap().is_empty());
+}
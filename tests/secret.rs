@@ -0,0 +1,30 @@
+use kms::{Key, SecretKey};
+use rand::rngs::OsRng;
+use zeroize::Zeroize;
+
+#[test]
+fn redacts_debug_output() {
+    let key = SecretKey::new([0xab; 4]);
+    assert_eq!(format!("{key:?}"), "SecretKey<4>(REDACTED)");
+    assert!(!format!("{key:#?}").contains("ab"));
+}
+
+#[test]
+fn compares_by_value() {
+    let key = Key::random(&mut OsRng);
+    assert_eq!(key, key.clone());
+    assert_eq!(key, Key::new(*key.as_bytes()));
+    assert_ne!(key, Key::random(&mut OsRng));
+
+    let mut bytes = *key.as_bytes();
+    bytes[31] ^= 1;
+    assert_ne!(key, Key::new(bytes));
+}
+
+#[test]
+fn zeroizes() {
+    let mut key = Key::new([0xff; 32]);
+    key.zeroize();
+    assert_eq!(key.as_bytes(), &[0; 32]);
+    assert_eq!(key, Key::default());
+}
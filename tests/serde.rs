@@ -0,0 +1,59 @@
+#![cfg(feature = "serde")]
+
+use std::fmt::Debug;
+
+use kms::{Key, KeyManagementScheme, Khf, Kwt, MemoryKms};
+use rand::rngs::OsRng;
+use serde::{de::DeserializeOwned, Serialize};
+
+const IDS: [u64; 5] = [0, 1, 7, 1000, u64::MAX];
+
+/// Commits keys for every id, leaves an update and a revocation in flight, and checks that each
+/// serializer round-trips exactly the committed state.
+fn round_trip<K>(mut kms: K)
+where
+    K: KeyManagementScheme<KeyId = u64, Key = Key> + Serialize + DeserializeOwned,
+    K::Error: Debug,
+{
+    let committed: Vec<_> = IDS.iter().map(|id| kms.derive(*id).unwrap()).collect();
+    kms.commit(OsRng).unwrap();
+    kms.update(IDS[1]).unwrap();
+    kms.revoke(IDS[2]).unwrap();
+
+    let json: K = serde_json::from_str(&serde_json::to_string(&kms).unwrap()).unwrap();
+    let bincode: K = bincode::deserialize(&bincode::serialize(&kms).unwrap()).unwrap();
+
+    for mut loaded in [json, bincode] {
+        for (id, key) in IDS.iter().zip(&committed) {
+            assert_eq!(loaded.derive(*id).unwrap(), *key);
+        }
+        assert!(loaded.commit(OsRng).unwrap().is_empty());
+    }
+}
+
+#[test]
+fn memory_round_trips() {
+    round_trip(MemoryKms::new(OsRng));
+}
+
+#[test]
+fn khf_round_trips() {
+    round_trip(Khf::new(OsRng, &[4, 4]));
+}
+
+#[test]
+fn kwt_round_trips() {
+    round_trip(Kwt::new(OsRng, 16));
+}
+
+#[test]
+fn rejects_invalid_state() {
+    let kwt = r#"{"fanout": 3, "root": [], "nodes": []}"#;
+    assert!(serde_json::from_str::<Kwt>(kwt).is_err());
+
+    let khf = r#"{"fanouts": [0], "roots": {}}"#;
+    assert!(serde_json::from_str::<Khf>(khf).is_err());
+
+    let memory = r#"{"keys": {"1": [1, 2, 3]}}"#;
+    assert!(serde_json::from_str::<MemoryKms>(memory).is_err());
+}
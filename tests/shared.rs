@@ -0,0 +1,139 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use kms::{Key, Khf, Kwt, MemoryKms, SharedDerive, SharedKms};
+use rand::rngs::OsRng;
+
+const IDS: u64 = 64;
+const ROUNDS: u64 = 200;
+const READERS: usize = 4;
+
+/// Every key each id has had, by the round of the commit that gave it.
+type History = BTreeMap<u64, BTreeMap<u64, Key>>;
+
+/// Returns the key of `id` as of the given round.
+fn key_at(history: &History, id: u64, round: u64) -> &Key {
+    history[&id].range(..=round).next_back().unwrap().1
+}
+
+/// Races readers deriving keys against a writer updating and committing them, checking that no
+/// reader is ever served a key that a commit had replaced before the derivation started.
+fn no_stale_keys<K>(kms: K)
+where
+    K: SharedDerive<KeyId = u64, Key = Key> + Send + Sync,
+    K::Error: std::fmt::Debug,
+{
+    let shared = SharedKms::new(kms);
+    let history = Mutex::new(History::new());
+    let round = AtomicU64::new(0);
+    let done = AtomicBool::new(false);
+
+    for id in 0..IDS {
+        let key = shared.derive(id).unwrap();
+        history
+            .lock()
+            .unwrap()
+            .entry(id)
+            .or_default()
+            .insert(0, key);
+    }
+    for (id, key) in shared.commit(OsRng).unwrap() {
+        history
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .unwrap()
+            .insert(0, key.unwrap());
+    }
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for r in 1..=ROUNDS {
+                let mut kms = shared.write();
+                kms.update((r * 7) % IDS).unwrap();
+
+                // Publish the commit before any reader can observe it.
+                let mut history = history.lock().unwrap();
+                for (id, key) in kms.commit(OsRng).unwrap() {
+                    history.get_mut(&id).unwrap().insert(r, key.unwrap());
+                }
+                round.store(r, Ordering::SeqCst);
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+
+        for reader in 0..READERS {
+            let (shared, history, round, done) = (&shared, &history, &round, &done);
+            s.spawn(move || {
+                let mut id = reader as u64;
+                while !done.load(Ordering::SeqCst) {
+                    id = (id + 5) % IDS;
+                    let before = round.load(Ordering::SeqCst);
+                    let key = shared.derive(id).unwrap();
+                    let after = round.load(Ordering::SeqCst);
+
+                    let history = history.lock().unwrap();
+                    assert!(
+                        (before..=after).any(|r| *key_at(&history, id, r) == key),
+                        "stale key for {id} between rounds {before} and {after}"
+                    );
+                }
+            });
+        }
+    });
+
+    let mut kms = shared.into_inner();
+    let history = history.into_inner().unwrap();
+    for id in 0..IDS {
+        assert_eq!(kms.derive(id).unwrap(), *key_at(&history, id, ROUNDS));
+    }
+}
+
+#[test]
+fn memory_serves_no_stale_keys() {
+    no_stale_keys(MemoryKms::new(OsRng));
+}
+
+#[test]
+fn khf_serves_no_stale_keys() {
+    no_stale_keys(Khf::new(OsRng, &[4, 4]));
+}
+
+#[test]
+fn kwt_serves_no_stale_keys() {
+    no_stale_keys(Kwt::new(OsRng, 16));
+}
+
+#[test]
+fn derives_missing_keys_exclusively() {
+    let shared = SharedKms::new(Kwt::new(OsRng, 16));
+    assert!(shared.read().try_derive(3).is_none());
+
+    let key = shared.derive(3).unwrap();
+    assert_eq!(shared.read().try_derive(3).unwrap().unwrap(), key);
+
+    shared.revoke(3).unwrap();
+    assert!(shared.read().try_derive(3).unwrap().is_err());
+}
+
+#[test]
+fn derives_batches_under_one_lock() {
+    let shared = SharedKms::new(Kwt::new(OsRng, 16));
+    let keys = shared.derive_many([3, 40, 3]).unwrap();
+    assert_eq!(keys[0], keys[2]);
+    assert_eq!(shared.derive(40).unwrap(), keys[1]);
+    assert_eq!(
+        shared.derive_many([40, 3]).unwrap(),
+        [keys[1].clone(), keys[0].clone()]
+    );
+
+    let updated = shared.update_many([3, 40]).unwrap();
+    assert_eq!(shared.derive_many([3, 40]).unwrap(), updated);
+    assert_ne!(updated[0], keys[0]);
+}
@@ -0,0 +1,57 @@
+use kms::{conformance, Cached, KeyManagementScheme, Khf, Kwt, MemoryKms, Staged};
+use rand::rngs::OsRng;
+
+const IDS: [u64; 8] = [0, 1, 2, 15, 16, 17, 255, u64::MAX];
+
+#[test]
+fn memory_aborts() {
+    conformance::abort_restores_keys(&mut MemoryKms::new(OsRng), &IDS);
+}
+
+#[test]
+fn khf_aborts() {
+    conformance::abort_restores_keys(&mut Khf::new(OsRng, &[4, 4]), &IDS);
+}
+
+#[test]
+fn kwt_aborts() {
+    conformance::abort_restores_keys(&mut Kwt::new(OsRng, 16), &IDS);
+}
+
+#[test]
+fn cached_aborts() {
+    conformance::abort_restores_keys(&mut Cached::new(Khf::new(OsRng, &[4, 4]), 4), &IDS);
+}
+
+#[test]
+fn dropped_guard_aborts() {
+    let mut kms = Khf::new(OsRng, &[4, 4]);
+    let key = kms.derive(7).unwrap();
+    kms.commit(OsRng).unwrap();
+
+    {
+        let mut staged = Staged::new(&mut kms);
+        assert_ne!(staged.update(7).unwrap(), key);
+    }
+    assert_eq!(kms.derive(7).unwrap(), key);
+
+    let mut staged = Staged::new(&mut kms);
+    staged.revoke(7).unwrap();
+    staged.abort();
+    assert_eq!(kms.derive(7).unwrap(), key);
+}
+
+#[test]
+fn committed_guard_keeps_updates() {
+    let mut kms = Kwt::new(OsRng, 16);
+    let key = kms.derive(7).unwrap();
+    kms.commit(OsRng).unwrap();
+
+    let mut staged = Staged::new(&mut kms);
+    let updated = staged.update(7).unwrap();
+    let changes = staged.commit(OsRng).unwrap();
+    assert!(changes.iter().any(|(id, _)| *id == 7));
+
+    assert_ne!(kms.derive(7).unwrap(), key);
+    assert_eq!(kms.derive(7).unwrap(), updated);
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use std::{fs, io};
+
+use kms::{FileStorage, KmsError, MemStorage, Storage};
+
+use common::Dir;
+
+fn storage(dir: &Dir) -> FileStorage {
+    FileStorage::new(dir.path("state")).unwrap()
+}
+
+fn replaces_and_discards(mut storage: impl Storage) {
+    assert_eq!(storage.read("kms").unwrap(), None);
+    storage.discard("kms").unwrap();
+
+    storage.replace("kms", b"first").unwrap();
+    storage.replace("other", b"other").unwrap();
+    assert_eq!(storage.read("kms").unwrap().as_deref(), Some(&b"first"[..]));
+
+    storage.replace("kms", b"second, and longer").unwrap();
+    assert_eq!(
+        storage.read("kms").unwrap().as_deref(),
+        Some(&b"second, and longer"[..])
+    );
+
+    storage.discard("kms").unwrap();
+    assert_eq!(storage.read("kms").unwrap(), None);
+    assert_eq!(
+        storage.read("other").unwrap().as_deref(),
+        Some(&b"other"[..])
+    );
+}
+
+#[test]
+fn memory_replaces_and_discards() {
+    replaces_and_discards(MemStorage::new());
+}
+
+#[test]
+fn file_replaces_and_discards() {
+    let dir = Dir::new("round-trip");
+    replaces_and_discards(storage(&dir));
+
+    // Nothing is left behind by the replacements.
+    let mut names: Vec<_> = fs::read_dir(dir.path("state"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["other"]);
+}
+
+#[test]
+fn file_replace_scrubs_old_contents() {
+    let dir = Dir::new("replace");
+    let mut storage = storage(&dir);
+    storage.replace("kms", b"secret").unwrap();
+
+    // A second link to the old file sees what is left of it once it has been replaced.
+    fs::hard_link(dir.path("state").join("kms"), dir.path("old")).unwrap();
+    storage.replace("kms", b"new").unwrap();
+    assert_eq!(fs::read(dir.path("old")).unwrap(), [0; 6]);
+    assert_eq!(storage.read("kms").unwrap().as_deref(), Some(&b"new"[..]));
+}
+
+#[test]
+fn file_discard_scrubs_contents() {
+    let dir = Dir::new("discard");
+    let mut storage = storage(&dir);
+    storage.replace("kms", b"secret").unwrap();
+
+    fs::hard_link(dir.path("state").join("kms"), dir.path("old")).unwrap();
+    storage.discard("kms").unwrap();
+    assert_eq!(fs::read(dir.path("old")).unwrap(), [0; 6]);
+    assert_eq!(storage.read("kms").unwrap(), None);
+}
+
+#[test]
+fn file_rejects_names_outside_directory() {
+    let dir = Dir::new("names");
+    let mut storage = storage(&dir);
+    fs::write(dir.path("outside"), b"outside").unwrap();
+
+    let invalid = |result: Result<(), KmsError>| match result {
+        Err(KmsError::Io(err)) => err.kind() == io::ErrorKind::InvalidInput,
+        _ => false,
+    };
+    for name in ["", "..", "../outside", "a/b", "/tmp/kms", "a\\b"] {
+        assert!(invalid(storage.replace(name, b"escaped").map(drop)));
+        assert!(invalid(storage.read(name).map(drop)));
+        assert!(invalid(storage.discard(name).map(drop)));
+    }
+    assert_eq!(fs::read(dir.path("outside")).unwrap(), b"outside");
+}
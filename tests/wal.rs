@@ -0,0 +1,229 @@
+use std::{
+    cell::{Cell, RefCell},
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    rc::Rc,
+};
+
+use kms::{KeyManagementScheme, Khf, Kwt, Medium, MemoryKms, Persist, Wal};
+use rand::rngs::OsRng;
+
+const IDS: [u64; 5] = [0, 1, 7, 1000, u64::MAX];
+const REVOKED: u64 = 64;
+
+/// An in-memory medium that crashes once its budget of operations runs out, tearing the write
+/// that exhausts it in half.
+struct Faulty {
+    bytes: Rc<RefCell<Vec<u8>>>,
+    pos: u64,
+    budget: Rc<Cell<usize>>,
+}
+
+impl Faulty {
+    fn spend(&self) -> io::Result<bool> {
+        match self.budget.get() {
+            0 => Err(io::Error::other("crashed")),
+            n => {
+                self.budget.set(n - 1);
+                Ok(n == 1)
+            }
+        }
+    }
+}
+
+impl Read for Faulty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.bytes.borrow();
+        let start = bytes.len().min(self.pos as usize);
+        let n = (&bytes[start..]).read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Faulty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let last = self.spend()?;
+        let buf = if last { &buf[..buf.len() / 2] } else { buf };
+
+        let mut bytes = self.bytes.borrow_mut();
+        let mut cursor = Cursor::new(&mut *bytes);
+        cursor.set_position(self.pos);
+        cursor.write_all(buf)?;
+        self.pos += buf.len() as u64;
+
+        if last {
+            Err(io::Error::other("crashed"))
+        } else {
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Faulty {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.bytes.borrow().len() as i64;
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::End(delta) => (len + delta) as u64,
+            SeekFrom::Current(delta) => (self.pos as i64 + delta) as u64,
+        };
+        Ok(self.pos)
+    }
+}
+
+impl Medium for Faulty {
+    fn sync(&mut self) -> io::Result<()> {
+        self.spend().map(drop)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.spend()?;
+        self.bytes.borrow_mut().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+/// A log and state medium sharing one crash budget.
+struct Disk {
+    log: Rc<RefCell<Vec<u8>>>,
+    state: Rc<RefCell<Vec<u8>>>,
+    budget: Rc<Cell<usize>>,
+}
+
+impl Disk {
+    fn new() -> Self {
+        Self {
+            log: Rc::default(),
+            state: Rc::default(),
+            budget: Rc::new(Cell::new(usize::MAX)),
+        }
+    }
+
+    fn media(&self) -> (Faulty, Faulty) {
+        let medium = |bytes: &Rc<RefCell<Vec<u8>>>| Faulty {
+            bytes: bytes.clone(),
+            pos: 0,
+            budget: self.budget.clone(),
+        };
+        (medium(&self.log), medium(&self.state))
+    }
+
+    fn snapshot(&self) -> Self {
+        Self {
+            log: Rc::new(RefCell::new(self.log.borrow().clone())),
+            state: Rc::new(RefCell::new(self.state.borrow().clone())),
+            budget: Rc::new(Cell::new(usize::MAX)),
+        }
+    }
+}
+
+fn derive_all<K: KeyManagementScheme<KeyId = u64>>(kms: &mut K) -> Vec<K::Key> {
+    kms.derive_many(IDS).unwrap()
+}
+
+/// Crashes a commit after every possible number of operations, checking that recovery always
+/// yields either the state before or the state after the commit.
+fn crash_at_every_step<K>(mut inner: K)
+where
+    K: Persist<KeyId = u64, Error = kms::KmsError>,
+    K::Key: PartialEq + std::fmt::Debug,
+{
+    let before = derive_all(&mut inner);
+    let revoked = inner.derive(REVOKED).unwrap();
+
+    let disk = Disk::new();
+    let (log, state) = disk.media();
+    Wal::create(inner, log, state).unwrap();
+
+    let (mut rolled_back, mut replayed) = (false, false);
+    for steps in 0.. {
+        let disk = disk.snapshot();
+        let (log, state) = disk.media();
+        let mut wal = Wal::<K, _>::open(log, state).unwrap();
+        wal.update(IDS[1]).unwrap();
+        wal.revoke(REVOKED).unwrap();
+
+        disk.budget.set(steps);
+        let committed = wal.commit(OsRng).is_ok();
+        let after = {
+            disk.budget.set(usize::MAX);
+            derive_all(&mut wal)
+        };
+        drop(wal);
+
+        let (log, state) = disk.media();
+        let mut wal = Wal::<K, _>::open(log, state).unwrap();
+        let recovered = derive_all(&mut wal);
+        if recovered == before {
+            assert!(
+                !committed,
+                "recovered a rolled back commit after {steps} steps"
+            );
+            rolled_back = true;
+        } else {
+            assert_eq!(
+                recovered, after,
+                "recovered a mixed state after {steps} steps"
+            );
+            assert_ne!(wal.derive(REVOKED).unwrap(), revoked);
+            replayed = true;
+        }
+
+        if committed {
+            break;
+        }
+    }
+
+    assert!(rolled_back && replayed);
+}
+
+#[test]
+fn memory_survives_crashes() {
+    crash_at_every_step(MemoryKms::new(OsRng));
+}
+
+#[test]
+fn khf_survives_crashes() {
+    crash_at_every_step(Khf::new(OsRng, &[4, 4]));
+}
+
+#[test]
+fn kwt_survives_crashes() {
+    crash_at_every_step(Kwt::new(OsRng, 16));
+}
+
+#[test]
+fn torn_state_without_log_is_corrupted() {
+    let disk = Disk::new();
+    let (log, state) = disk.media();
+    Wal::create(MemoryKms::new(OsRng), log, state).unwrap();
+
+    disk.state.borrow_mut().pop();
+    let (log, state) = disk.media();
+    assert!(Wal::<MemoryKms, _>::open(log, state).is_err());
+}
+
+#[test]
+fn commit_scrubs_revoked_keys() {
+    let disk = Disk::new();
+    let (log, state) = disk.media();
+    let mut wal = Wal::create(MemoryKms::new(OsRng), log, state).unwrap();
+    let keys = wal.derive_many(0..8).unwrap();
+    wal.commit(OsRng).unwrap();
+
+    for id in 0..8 {
+        wal.revoke(id).unwrap();
+    }
+    wal.commit(OsRng).unwrap();
+
+    for medium in [&disk.log, &disk.state] {
+        let bytes = medium.borrow();
+        for key in &keys {
+            assert!(!bytes.windows(32).any(|window| window == key.as_bytes()));
+        }
+    }
+}